@@ -27,16 +27,36 @@ const injectFrameAnimation = () => {
 // Force rerenders
 const frameForcer = injectFrameAnimation();
 
+// Connection to the server, set once capture starts
+let client = null;
+let recorder = null;
+
 window.addEventListener("message", async (event) => {
   if (event.source !== window) return;
+  if (event.data.type === "PAGE_MESSAGE") {
+    if (client && client.readyState === WebSocket.OPEN) {
+      client.send(JSON.stringify(event.data.message));
+    } else {
+      console.warn("dropping page message, not connected:", event.data.message);
+    }
+  }
   if (event.data.type === "CAPTURE_COMMAND") {
     if (event.data.command === "open-popup") {
       chrome.runtime.sendMessage({
         command: "open-popup",
       });
     }
+    if (event.data.command === "stop") {
+      // onstop closes the connection, so the server accepts the next tab's
+      if (recorder && recorder.state !== "inactive") recorder.stop();
+      else if (client && client.readyState === WebSocket.OPEN) client.close();
+      recorder = null;
+    }
     if (event.data.command === "start") {
-      const client = new WebSocket(`ws://localhost:${event.data.port}`, []);
+      if (recorder && recorder.state !== "inactive") recorder.stop();
+      else if (client && client.readyState === WebSocket.OPEN) client.close();
+      recorder = null;
+      client = new WebSocket(`ws://localhost:${event.data.port}`, []);
 
       await new Promise((resolve) => {
         if (client.readyState === WebSocket.OPEN) resolve();
@@ -45,6 +65,23 @@ window.addEventListener("message", async (event) => {
 
       client.send("hello from extension");
 
+      // a later start replaces `client` and `recorder`, these handlers keep using their own
+      const connection = client;
+      window.postMessage({ type: "EXTENSION_STATE", connected: true }, "*");
+      connection.addEventListener("close", () => {
+        if (client === connection) {
+          window.postMessage({ type: "EXTENSION_STATE", connected: false }, "*");
+        }
+      });
+
+      client.onmessage = async (e) => {
+        console.log(e);
+        window.postMessage({ type: "EXTENSION", message: e.data }, "*");
+      };
+
+      // the server captures the tab itself, only page messages go through here
+      if (event.data.record === false) return;
+
       const streamIdPromise = new Promise((resolve) => {
         const messageListener = (message) => {
           if (message.command === "stream-id") {
@@ -84,41 +121,37 @@ window.addEventListener("message", async (event) => {
         },
       });
 
-      const recorder = new MediaRecorder(stream, {
+      const mediaRecorder = new MediaRecorder(stream, {
         audioBitsPerSecond: 128_000,
         videoBitsPerSecond: 2_500_000,
         mimeType: "video/webm",
       });
+      recorder = mediaRecorder;
 
-      recorder.ondataavailable = async (e) => {
+      mediaRecorder.ondataavailable = async (e) => {
         if (!e.data.size) return;
         const buffer = await e.data.arrayBuffer();
-        client.send(buffer);
+        connection.send(buffer);
       };
 
-      recorder.onerror = (e) => {
+      mediaRecorder.onerror = (e) => {
         console.error("mediarecorder error:", e);
-        client.send(`mediarecorder error: ${e}`);
-        recorder.stop();
+        connection.send(`mediarecorder error: ${e}`);
+        mediaRecorder.stop();
       };
 
-      recorder.onstop = function () {
+      mediaRecorder.onstop = function () {
         const tracks = stream.getTracks();
         tracks.forEach(function (track) {
           track.stop();
         });
-        if (client.readyState === WebSocket.OPEN) client.close();
+        if (connection.readyState === WebSocket.OPEN) connection.close();
       };
 
-      recorder.start(21);
+      mediaRecorder.start(21);
 
-      console.log(recorder.state);
+      console.log(mediaRecorder.state);
       console.log("started recorder");
-
-      client.onmessage = async (e) => {
-        console.log(e);
-        window.postMessage({ type: "EXTENSION", message: e.data }, "*");
-      };
     }
   }
 });
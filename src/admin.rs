@@ -0,0 +1,246 @@
+use axum::{
+    Json, Router,
+    extract::{Request, State},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::{net::SocketAddr, sync::Arc};
+use subtle::ConstantTimeEq;
+use tokio::{
+    net::TcpListener,
+    spawn,
+    sync::{mpsc::Sender, oneshot},
+    task::JoinHandle,
+};
+use tracing::{info, warn};
+
+use crate::{
+    control::Control,
+    encoder::EncoderControl,
+    metrics,
+    page::PageSender,
+    status::Status,
+    twitch::{Metadata, StreamAction},
+};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    /// Address to listen on, local only by default.
+    pub bind: String,
+    pub port: u16,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        AdminConfig {
+            bind: "127.0.0.1".to_string(),
+            port: 8081,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct AdminState {
+    /// The API is disabled without a token.
+    token: Option<String>,
+    control_tx: Sender<Control>,
+    encoder_tx: Sender<EncoderControl>,
+    page: PageSender,
+    metadata: Arc<Metadata>,
+    status: Status,
+}
+
+/// Serves the status dashboard, the admin API and Prometheus metrics. Every
+/// `/api` request needs `Authorization: Bearer <token>`, `/metrics` is open.
+pub async fn run_admin(
+    config: AdminConfig,
+    token: Option<String>,
+    control_tx: Sender<Control>,
+    encoder_tx: Sender<EncoderControl>,
+    page: PageSender,
+    metadata: Arc<Metadata>,
+    status: Status,
+) -> JoinHandle<()> {
+    let state = AdminState {
+        token,
+        control_tx,
+        encoder_tx,
+        page,
+        metadata,
+        status,
+    };
+    let api = Router::new()
+        .route("/status", get(get_status))
+        .route("/reload", post(reload))
+        .route("/navigate", post(navigate))
+        .route("/scene", post(switch_scene))
+        .route("/encoder/restart", post(restart_encoder))
+        .route("/output/start", post(start_output))
+        .route("/output/stop", post(stop_output))
+        .route("/inject", post(inject))
+        .route("/marker", post(create_marker))
+        .route("/clip", post(create_clip))
+        .route("/screenshot", get(screenshot))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authorize));
+    let app = Router::new()
+        .route("/", get(dashboard))
+        .route("/metrics", get(get_metrics))
+        .nest("/api", api)
+        .with_state(state);
+
+    let addr = format!("{}:{}", config.bind, config.port)
+        .parse::<SocketAddr>()
+        .unwrap();
+    let listener = TcpListener::bind(&addr).await.unwrap();
+    info!("admin listening on: http://{}", addr);
+    spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            warn!("admin server stopped: {}", e);
+        }
+    })
+}
+
+async fn authorize(State(state): State<AdminState>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .zip(state.token.as_deref())
+        // constant time, so the token can't be guessed from response times
+        .is_some_and(|(token, expected)| token.as_bytes().ct_eq(expected.as_bytes()).into());
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(request).await
+}
+
+async fn dashboard() -> Html<&'static str> {
+    Html(include_str!("dashboard.html"))
+}
+
+async fn get_metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(),
+    )
+}
+
+async fn get_status(State(state): State<AdminState>) -> Json<Value> {
+    Json(state.status.snapshot())
+}
+
+async fn reload(State(state): State<AdminState>) -> StatusCode {
+    control(&state, Control::Reload).await
+}
+
+#[derive(Deserialize)]
+struct NavigateRequest {
+    url: String,
+}
+
+async fn navigate(
+    State(state): State<AdminState>,
+    Json(request): Json<NavigateRequest>,
+) -> StatusCode {
+    control(&state, Control::Navigate(request.url)).await
+}
+
+#[derive(Deserialize)]
+struct SceneRequest {
+    scene: String,
+}
+
+async fn switch_scene(
+    State(state): State<AdminState>,
+    Json(request): Json<SceneRequest>,
+) -> StatusCode {
+    control(&state, Control::SwitchScene(request.scene)).await
+}
+
+async fn restart_encoder(State(state): State<AdminState>) -> StatusCode {
+    encoder(&state, EncoderControl::Restart).await
+}
+
+async fn start_output(State(state): State<AdminState>) -> StatusCode {
+    encoder(&state, EncoderControl::Start).await
+}
+
+async fn stop_output(State(state): State<AdminState>) -> StatusCode {
+    encoder(&state, EncoderControl::Stop).await
+}
+
+/// Sends any message to the page as if it came from the server, e.g. a fake
+/// `alert` or `twitch-chat` to test an overlay.
+async fn inject(State(state): State<AdminState>, Json(message): Json<Value>) -> StatusCode {
+    if !message["type"].is_string() {
+        return StatusCode::UNPROCESSABLE_ENTITY;
+    }
+    info!("injecting page message: {}", message);
+    state.page.send(message).await;
+    StatusCode::ACCEPTED
+}
+
+#[derive(Deserialize)]
+struct MarkerRequest {
+    description: Option<String>,
+}
+
+/// Creates a stream marker, the body and its `description` are optional.
+async fn create_marker(
+    State(state): State<AdminState>,
+    request: Option<Json<MarkerRequest>>,
+) -> Response {
+    let description = request.and_then(|Json(request)| request.description);
+    stream_action(&state, StreamAction::Marker(description)).await
+}
+
+async fn create_clip(State(state): State<AdminState>) -> Response {
+    stream_action(&state, StreamAction::Clip).await
+}
+
+async fn stream_action(state: &AdminState, action: StreamAction) -> Response {
+    match state.metadata.run(action).await {
+        Some(result) => Json(result).into_response(),
+        None => (
+            StatusCode::BAD_GATEWAY,
+            Json(json!({ "error": "twitch refused, is the stream live?" })),
+        )
+            .into_response(),
+    }
+}
+
+async fn screenshot(State(state): State<AdminState>) -> Response {
+    let (tx, rx) = oneshot::channel();
+    if control(&state, Control::Screenshot(tx)).await != StatusCode::ACCEPTED {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    match rx.await {
+        Ok(Some(png)) => ([(header::CONTENT_TYPE, "image/png")], png).into_response(),
+        _ => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "error": "no screenshot, is the browser capturing?" })),
+        )
+            .into_response(),
+    }
+}
+
+async fn control(state: &AdminState, control: Control) -> StatusCode {
+    info!("admin control: {:?}", control);
+    match state.control_tx.send(control).await {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+async fn encoder(state: &AdminState, control: EncoderControl) -> StatusCode {
+    match state.encoder_tx.send(control).await {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
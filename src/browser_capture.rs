@@ -1,16 +1,158 @@
-use chromiumoxide::{Browser, BrowserConfig, Page, cdp::browser_protocol::log::EventEntryAdded};
+use chromiumoxide::{
+    Browser, BrowserConfig, Page,
+    cdp::browser_protocol::{
+        inspector::EventTargetCrashed, page::CaptureScreenshotFormat, target::TargetId,
+    },
+    error::CdpError,
+    page::ScreenshotParams,
+};
 use futures_util::StreamExt;
-use std::path::Path;
-use tokio::{spawn, task::JoinHandle};
-use tracing::{debug, info, warn};
+use serde::Deserialize;
+use std::{collections::HashMap, path::Path, time::Duration};
+use tokio::{
+    select, spawn,
+    sync::mpsc,
+    task::JoinHandle,
+    time::{Instant, Interval, MissedTickBehavior, interval, sleep, timeout},
+};
+use tracing::{info, warn};
+
+use crate::{
+    browser_log::{BrowserLogConfig, forward_page_log},
+    capture::{Backend, CaptureBackend, ScreencastConfig, TestPatternConfig, X11Config},
+    metrics,
+    protocol::sdk_script,
+    scenes::{MAIN_SCENE, ScenesConfig, Transition},
+};
+
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+/// How long the page may take to answer a probe or a reload before it counts as hung.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CaptureConfig {
+    /// Also wait for the page to call `webstreamer.ready()` before capturing.
+    pub wait_for_page: bool,
+    /// Milliseconds to wait for readiness before capturing anyway.
+    pub ready_timeout: u64,
+    /// Milliseconds between the `navigate` page message and navigating.
+    pub transition: u64,
+    pub backend: Backend,
+    /// Used by the `screencast` backend.
+    pub screencast: ScreencastConfig,
+    /// Used by the `x11` backend.
+    pub x11: X11Config,
+    /// Used by the `test-pattern` backend.
+    pub test_pattern: TestPatternConfig,
+    /// Logging of the page's console, exceptions and failed requests.
+    pub log: BrowserLogConfig,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        CaptureConfig {
+            wait_for_page: false,
+            ready_timeout: 15000,
+            transition: 1000,
+            backend: Backend::default(),
+            screencast: ScreencastConfig::default(),
+            x11: X11Config::default(),
+            test_pattern: TestPatternConfig::default(),
+            log: BrowserLogConfig::default(),
+        }
+    }
+}
+
+/// What the injected SDK has seen in the current document, see `sdk.js`.
+#[derive(Debug, Default, Deserialize)]
+struct Readiness {
+    extension: bool,
+    page: bool,
+}
+
+/// Why the captured page stopped working, from the mildest to the most drastic
+/// recovery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The renderer crashed, the page is reloaded.
+    Crashed,
+    /// The page kept throwing uncaught exceptions, it is reloaded.
+    Exceptions,
+    /// The page stopped answering, its tab is recreated.
+    Hung,
+    /// Chrome exited, it is relaunched.
+    Exited,
+}
 
 pub struct CapturedBrowser {
     browser: Browser,
     handle: JoinHandle<()>,
+    config: CaptureConfig,
+    backend: Box<dyn CaptureBackend>,
+    scenes_config: ScenesConfig,
+    window_size: (u32, u32),
+    headless: bool,
+    /// The url capture was last started on, reopened by recovery.
+    url: String,
+    /// The url of the main scene's tab.
+    main_url: String,
+    /// The scene of the captured tab.
+    scene: String,
+    /// Preloaded tabs of the other scenes.
+    scenes: HashMap<String, Page>,
+    /// Failures with the tab they happened in, `None` for Chrome itself.
+    failure_tx: mpsc::Sender<(Option<TargetId>, Failure)>,
+    failure_rx: mpsc::Receiver<(Option<TargetId>, Failure)>,
+    probe: Interval,
 }
 
 impl CapturedBrowser {
-    pub async fn new(window_size: (u32, u32), headless: bool) -> Self {
+    pub async fn new(
+        config: CaptureConfig,
+        backend: Box<dyn CaptureBackend>,
+        scenes_config: ScenesConfig,
+        window_size: (u32, u32),
+        headless: bool,
+    ) -> Self {
+        let (failure_tx, failure_rx) = mpsc::channel(10);
+        if headless && backend.needs_window() {
+            info!("running chrome headful for the capture backend");
+        }
+        let headless = headless && !backend.needs_window();
+        let (browser, handle) =
+            Self::launch(window_size, headless, backend.as_ref(), failure_tx.clone())
+                .await
+                .unwrap();
+        let mut probe = interval(PROBE_INTERVAL);
+        probe.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        CapturedBrowser {
+            browser,
+            handle,
+            config,
+            backend,
+            scenes_config,
+            window_size,
+            headless,
+            url: String::new(),
+            main_url: String::new(),
+            scene: MAIN_SCENE.to_string(),
+            scenes: HashMap::new(),
+            failure_tx,
+            failure_rx,
+            probe,
+        }
+    }
+
+    /// Starts Chrome with the capture extension and whatever `backend` needs.
+    /// The handler reports an `Exited` failure once Chrome's connection is gone.
+    async fn launch(
+        window_size: (u32, u32),
+        headless: bool,
+        backend: &dyn CaptureBackend,
+        failure_tx: mpsc::Sender<(Option<TargetId>, Failure)>,
+    ) -> Result<(Browser, JoinHandle<()>), CdpError> {
         let extension_path = Path::new("./extension").canonicalize().unwrap();
         let extension_id = include_str!("../extension/id.txt").trim();
         let (browser, mut handler) = Browser::launch(
@@ -44,17 +186,22 @@ impl CapturedBrowser {
                 .arg("--enable-font-subpixel-positioning")
                 .arg("--disable-font-antialiasing=false")
                 .arg("--force-color-profile=srgb")
+                // keeps preloaded scene tabs running in the background
+                .arg("--disable-background-timer-throttling")
+                .arg("--disable-renderer-backgrounding")
+                .arg("--disable-backgrounding-occluded-windows")
                 .arg("--force-device-scale-factor=1")
                 .arg(if headless { "--headless=new" } else { "" })
                 .arg(format!("--allowlisted-extension-id={}", extension_id))
+                .args(backend.browser_args())
+                .envs(backend.browser_env())
                 .disable_default_args()
                 .window_size(window_size.0, window_size.1)
                 .viewport(None)
                 .build()
                 .unwrap(),
         )
-        .await
-        .unwrap();
+        .await?;
 
         let handle = spawn(async move {
             while let Some(h) = handler.next().await {
@@ -62,40 +209,422 @@ impl CapturedBrowser {
                     warn!("invalid message in handler: {:?}", h);
                 }
             }
+            warn!("browser connection closed");
+            let _ = failure_tx.send((None, Failure::Exited)).await;
         });
-        CapturedBrowser { browser, handle }
+        Ok((browser, handle))
     }
 
-    pub async fn start_capture(&mut self, url: &str, ws_port: u16) -> Page {
-        let page = self.browser.new_page(url).await.unwrap();
-        page.wait_for_navigation_response().await.unwrap();
+    pub async fn start_capture(&mut self, url: &str) -> Page {
+        self.url = url.to_string();
+        self.main_url = url.to_string();
+        let page = loop {
+            if let Some(page) = self.open_tab().await {
+                break page;
+            }
+            // where `recover` ends up when tabs can't be opened
+            metrics::inc(
+                "webstreamer_browser_recoveries_total",
+                &[("action", "relaunch")],
+            );
+            sleep(PROBE_INTERVAL).await;
+            self.relaunch_browser().await;
+        };
+        self.preload_scenes().await;
+        self.start(&page).await;
+        page
+    }
 
-        let mut events = page.event_listener::<EventEntryAdded>().await.unwrap();
+    /// The url of the captured tab.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Opens a tab on `url`, logging what happens in it and reporting renderer
+    /// crashes as failures. The listeners are attached to the blank tab, so
+    /// they also see the first load.
+    async fn open_page(&self, url: &str) -> Result<Page, CdpError> {
+        let page = self.browser.new_page("about:blank").await?;
+        page.evaluate_on_new_document(sdk_script()).await?;
+        page.evaluate_on_new_document(include_str!("transition.js"))
+            .await?;
+
+        forward_page_log(&page, self.config.log.clone(), self.failure_tx.clone()).await?;
+
+        let mut crashes = page.event_listener::<EventTargetCrashed>().await?;
+        let failure_tx = self.failure_tx.clone();
+        let target = page.target_id().clone();
         spawn(async move {
-            while let Some(event) = events.next().await {
-                debug!(
-                    "brower log: [{:?}] {:?}",
-                    event.entry.level, event.entry.text
-                );
+            while crashes.next().await.is_some() {
+                warn!("page crashed");
+                metrics::inc("webstreamer_browser_crashes_total", &[]);
+                let _ = failure_tx
+                    .send((Some(target.clone()), Failure::Crashed))
+                    .await;
             }
         });
 
-        page.evaluate(format!(
-            r#"
-                window.postMessage({{
-                    type: 'CAPTURE_COMMAND',
-                    command: 'start',
-                    port: {}
-                }}, '*');
-                console.log("[rs] sent start capture message");
-                "#,
-            ws_port
-        ))
-        .await
-        .unwrap();
-        info!("sent start message");
+        page.goto(url).await?;
+        Ok(page)
+    }
 
-        page
+    /// Opens a background tab for every configured scene except the one shown.
+    async fn preload_scenes(&mut self) {
+        for scene in self.scenes_config.list.clone() {
+            if scene.name == self.scene || self.scenes.contains_key(&scene.name) {
+                continue;
+            }
+            info!("preloading scene {} at {}", scene.name, scene.url);
+            match timeout(PROBE_TIMEOUT, self.open_page(&scene.url)).await {
+                Ok(Ok(page)) => {
+                    self.scenes.insert(scene.name, page);
+                }
+                Ok(Err(e)) => warn!("failed to preload scene {}: {}", scene.name, e),
+                Err(_) => warn!("preloading scene {} timed out", scene.name),
+            }
+        }
+    }
+
+    /// Moves capture to the preloaded tab of `scene`, with its transition.
+    /// Returns false for scenes without a tab, which the page shows itself.
+    pub async fn switch_scene(&mut self, page: &mut Page, scene: &str) -> bool {
+        if scene == self.scene {
+            return true;
+        }
+        let Some(url) = self.scene_url(scene) else {
+            return false;
+        };
+        let mut next = match self.scenes.remove(scene) {
+            Some(next) => next,
+            None => match self.open_page(&url).await {
+                Ok(next) => next,
+                Err(e) => {
+                    warn!("failed to open scene {}: {}", scene, e);
+                    return false;
+                }
+            },
+        };
+        // a background tab may have stopped answering unnoticed
+        if !matches!(timeout(PROBE_TIMEOUT, next.evaluate("1")).await, Ok(Ok(_))) {
+            warn!("scene {} stopped answering, reopening it", scene);
+            let _ = timeout(PROBE_TIMEOUT, next.close()).await;
+            next = match self.open_page(&url).await {
+                Ok(next) => next,
+                Err(e) => {
+                    warn!("failed to reopen scene {}: {}", scene, e);
+                    return false;
+                }
+            };
+        }
+
+        info!("switching scene from {} to {}", self.scene, scene);
+        let transition = self.scenes_config.transition(scene);
+        if let Transition::Fade(duration) = transition {
+            fade(page, 1, duration).await;
+            fade(&next, 1, 0).await;
+        }
+        self.backend.stop(page).await;
+        let previous = std::mem::replace(page, next);
+        let previous_scene = std::mem::replace(&mut self.scene, scene.to_string());
+        self.url = url;
+        self.probe.reset();
+        self.start(page).await;
+        if let Transition::Fade(duration) = transition {
+            fade(page, 0, duration).await;
+        }
+        // ready to be switched back to without a black frame
+        fade(&previous, 0, 0).await;
+        self.scenes.insert(previous_scene, previous);
+        true
+    }
+
+    /// The url of `scene`, if it has a tab.
+    fn scene_url(&self, scene: &str) -> Option<String> {
+        if scene == MAIN_SCENE {
+            return Some(self.main_url.clone());
+        }
+        self.scenes_config.get(scene).map(|scene| scene.url.clone())
+    }
+
+    /// Reloads the page and restarts capture once the new document is loaded.
+    pub async fn reload(&mut self, page: &Page) {
+        info!("reloading page");
+        if let Err(e) = page.reload().await {
+            warn!("failed to reload page: {}", e);
+            return;
+        }
+        self.start(page).await;
+    }
+
+    /// Reloads the preloaded tabs whose url starts with `prefix`, the captured
+    /// tab is left alone.
+    pub async fn reload_scenes(&self, prefix: &str) {
+        for (scene, page) in &self.scenes {
+            if !matches!(page.url().await, Ok(Some(url)) if url.starts_with(prefix)) {
+                continue;
+            }
+            info!("reloading scene {}", scene);
+            if !matches!(timeout(PROBE_TIMEOUT, page.reload()).await, Ok(Ok(_))) {
+                warn!("failed to reload scene {}", scene);
+            }
+        }
+    }
+
+    /// Loads another url in the captured tab and restarts capture there.
+    pub async fn navigate(&mut self, page: &Page, url: &str) {
+        info!("navigating to {}", url);
+        if let Err(e) = page.goto(url).await {
+            warn!("failed to navigate to {}: {}", url, e);
+            return;
+        }
+        self.url = url.to_string();
+        if self.scene == MAIN_SCENE {
+            self.main_url = url.to_string();
+        }
+        self.start(page).await;
+    }
+
+    /// Resolves with the next failure: a renderer crash, Chrome exiting, or a
+    /// page that stops answering the periodic liveness probe. Crashed scene
+    /// tabs in the background are reloaded on the spot. Cancel safe.
+    pub async fn watch(&mut self, page: &Page) -> Failure {
+        loop {
+            select! {
+                Some((target, failure)) = self.failure_rx.recv() => {
+                    match target {
+                        Some(target) if &target != page.target_id() => {
+                            self.reload_scene(&target).await;
+                        }
+                        _ => return failure,
+                    }
+                }
+                _ = self.probe.tick() => {
+                    if let Some(failure) = self.check(page).await {
+                        return failure;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn reload_scene(&self, target: &TargetId) {
+        let Some((scene, page)) = self
+            .scenes
+            .iter()
+            .find(|(_, page)| page.target_id() == target)
+        else {
+            return;
+        };
+        info!("reloading crashed scene {}", scene);
+        metrics::inc(
+            "webstreamer_browser_recoveries_total",
+            &[("action", "reload_scene")],
+        );
+        if !matches!(timeout(PROBE_TIMEOUT, page.reload()).await, Ok(Ok(_))) {
+            warn!("failed to reload scene {}", scene);
+        }
+    }
+
+    async fn check(&mut self, page: &Page) -> Option<Failure> {
+        if !matches!(self.browser.try_wait(), Ok(None)) {
+            warn!("browser process exited");
+            return Some(Failure::Exited);
+        }
+        match timeout(PROBE_TIMEOUT, page.evaluate("1")).await {
+            Ok(Ok(_)) => None,
+            Ok(Err(e)) => {
+                warn!("page liveness probe failed: {}", e);
+                Some(Failure::Hung)
+            }
+            Err(_) => {
+                warn!("page did not answer the liveness probe");
+                Some(Failure::Hung)
+            }
+        }
+    }
+
+    /// Brings capture back after `failure`, escalating from reloading the page
+    /// to recreating the tab to relaunching Chrome until one works, then
+    /// restarts capture.
+    pub async fn recover(&mut self, page: &mut Page, failure: Failure) {
+        let mut failure = failure;
+        loop {
+            let (action, recovered) = match failure {
+                Failure::Crashed | Failure::Exceptions => ("reload", self.reload_page(page).await),
+                Failure::Hung => ("recreate_tab", self.recreate_tab(page).await),
+                Failure::Exited => ("relaunch", self.relaunch(page).await),
+            };
+            metrics::inc(
+                "webstreamer_browser_recoveries_total",
+                &[("action", action)],
+            );
+            if recovered {
+                info!("recovered from {:?} with {}", failure, action);
+                break;
+            }
+            warn!("{} did not recover from {:?}", action, failure);
+            failure = match failure {
+                Failure::Crashed | Failure::Exceptions => Failure::Hung,
+                Failure::Hung | Failure::Exited => {
+                    sleep(PROBE_INTERVAL).await;
+                    Failure::Exited
+                }
+            };
+        }
+        // failures reported by the replaced tab or browser are stale now
+        while self.failure_rx.try_recv().is_ok() {}
+        self.probe.reset();
+        self.start(page).await;
+    }
+
+    async fn reload_page(&self, page: &Page) -> bool {
+        info!("reloading page");
+        matches!(timeout(PROBE_TIMEOUT, page.reload()).await, Ok(Ok(_)))
+    }
+
+    /// Opens a tab on the captured url.
+    async fn open_tab(&self) -> Option<Page> {
+        match timeout(PROBE_TIMEOUT, self.open_page(&self.url)).await {
+            Ok(Ok(page)) => Some(page),
+            Ok(Err(e)) => {
+                warn!("failed to open tab: {}", e);
+                None
+            }
+            Err(_) => {
+                warn!("opening a tab timed out");
+                None
+            }
+        }
+    }
+
+    async fn recreate_tab(&self, page: &mut Page) -> bool {
+        info!("recreating tab");
+        let Some(new_page) = self.open_tab().await else {
+            return false;
+        };
+        let old_page = std::mem::replace(page, new_page);
+        if let Ok(Err(e)) = timeout(PROBE_TIMEOUT, old_page.close()).await {
+            warn!("failed to close old tab: {}", e);
+        }
+        true
+    }
+
+    async fn relaunch(&mut self, page: &mut Page) -> bool {
+        if !self.relaunch_browser().await || !self.recreate_tab(page).await {
+            return false;
+        }
+        self.preload_scenes().await;
+        true
+    }
+
+    /// Replaces Chrome, without any tabs.
+    async fn relaunch_browser(&mut self) -> bool {
+        info!("relaunching browser");
+        self.handle.abort();
+        self.browser.kill().await;
+        let launched = Self::launch(
+            self.window_size,
+            self.headless,
+            self.backend.as_ref(),
+            self.failure_tx.clone(),
+        );
+        match launched.await {
+            Ok((browser, handle)) => {
+                self.browser = browser;
+                self.handle = handle;
+            }
+            Err(e) => {
+                warn!("failed to launch browser: {}", e);
+                return false;
+            }
+        }
+        // the scene tabs went down with the old browser
+        self.scenes.clear();
+        true
+    }
+
+    pub async fn screenshot(&self, page: &Page) -> Option<Vec<u8>> {
+        let params = ScreenshotParams::builder()
+            .format(CaptureScreenshotFormat::Png)
+            .build();
+        match page.screenshot(params).await {
+            Ok(png) => Some(png),
+            Err(e) => {
+                warn!("failed to take screenshot: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Starts capture once the page is ready, see [`CapturedBrowser::wait_until_ready`].
+    async fn start(&mut self, page: &Page) {
+        // background tabs can't be captured
+        if let Err(e) = page.bring_to_front().await {
+            warn!("failed to bring tab to front: {}", e);
+        }
+        self.wait_until_ready(page).await;
+        self.backend.start(page).await;
+    }
+
+    /// Waits for the extension's content script to announce itself when it
+    /// captures and, with `wait_for_page`, for the page to call
+    /// `webstreamer.ready()`. Gives up after `ready_timeout`, logging the
+    /// steps that never happened.
+    async fn wait_until_ready(&self, page: &Page) {
+        let needs_extension = self.config.backend == Backend::Extension;
+        let started = Instant::now();
+        let deadline = started + Duration::from_millis(self.config.ready_timeout);
+        let mut readiness = Readiness::default();
+        loop {
+            if let Ok(result) = page.evaluate("window.__webstreamerReadiness").await
+                && let Ok(current) = result.into_value::<Readiness>()
+            {
+                readiness = current;
+            }
+            if (readiness.extension || !needs_extension)
+                && (readiness.page || !self.config.wait_for_page)
+            {
+                info!("page ready for capture after {:?}", started.elapsed());
+                return;
+            }
+            if Instant::now() >= deadline {
+                break;
+            }
+            sleep(READY_POLL_INTERVAL).await;
+        }
+
+        let mut missing = Vec::new();
+        if needs_extension && !readiness.extension {
+            missing.push("the extension content script never announced itself");
+        }
+        if self.config.wait_for_page && !readiness.page {
+            missing.push("the page never called webstreamer.ready()");
+        }
+        warn!(
+            "page not ready after {}ms, starting capture anyway: {}",
+            self.config.ready_timeout,
+            missing.join(", ")
+        );
+    }
+
+    /// Closes Chrome, then shuts the backend down, which takes down what it
+    /// started.
+    pub async fn shutdown(mut self) {
+        info!("closing browser");
+        self.handle.abort();
+        self.browser.kill().await;
+        self.backend.shutdown().await;
+    }
+}
+
+/// Fades the page's black transition overlay, see `transition.js`.
+async fn fade(page: &Page, opacity: u8, duration: u64) {
+    let fade = page.evaluate(format!("window.__webstreamerFade({opacity}, {duration})"));
+    let limit = PROBE_TIMEOUT + Duration::from_millis(duration);
+    match timeout(limit, fade).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => warn!("failed to fade transition overlay: {}", e),
+        Err(_) => warn!("fading transition overlay timed out"),
     }
 }
 
@@ -0,0 +1,257 @@
+use chromiumoxide::{
+    Page,
+    cdp::{
+        browser_protocol::{
+            log::{self, EventEntryAdded, LogEntryLevel, LogEntrySource},
+            network::{
+                EventLoadingFailed, EventLoadingFinished, EventRequestWillBeSent,
+                EventResponseReceived, RequestId,
+            },
+            target::TargetId,
+        },
+        js_protocol::runtime::{
+            ConsoleApiCalledType, EventConsoleApiCalled, EventExceptionThrown, RemoteObject,
+            StackTrace,
+        },
+    },
+    error::CdpError,
+};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+use tokio::{select, spawn, sync::mpsc, time::Instant};
+use tracing::{Level, debug, error, info, trace, warn};
+
+use crate::browser_capture::Failure;
+
+/// Requests whose urls are remembered for failures, a page that never
+/// finishes its requests can't grow the map beyond this.
+const MAX_PENDING_REQUESTS: usize = 1000;
+
+/// How the page's console, uncaught exceptions and failed requests are logged,
+/// under the `browser` target.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BrowserLogConfig {
+    /// Most messages logged per second per tab, the rest are only counted.
+    /// 0 logs everything.
+    pub rate_limit: u32,
+    /// Reload the page after this many uncaught exceptions within
+    /// `exception_window` seconds, 0 never does.
+    pub reload_after_exceptions: usize,
+    pub exception_window: u64,
+}
+
+impl Default for BrowserLogConfig {
+    fn default() -> Self {
+        BrowserLogConfig {
+            rate_limit: 50,
+            reload_after_exceptions: 0,
+            exception_window: 60,
+        }
+    }
+}
+
+/// Logs `page`'s console, exceptions, browser log entries and failed
+/// requests, reporting an `Exceptions` failure when it keeps throwing.
+pub async fn forward_page_log(
+    page: &Page,
+    config: BrowserLogConfig,
+    failure_tx: mpsc::Sender<(Option<TargetId>, Failure)>,
+) -> Result<(), CdpError> {
+    page.execute(log::EnableParams::default()).await?;
+    let mut console = page.event_listener::<EventConsoleApiCalled>().await?;
+    let mut exceptions = page.event_listener::<EventExceptionThrown>().await?;
+    let mut entries = page.event_listener::<EventEntryAdded>().await?;
+    let mut requests = page.event_listener::<EventRequestWillBeSent>().await?;
+    let mut responses = page.event_listener::<EventResponseReceived>().await?;
+    let mut finished = page.event_listener::<EventLoadingFinished>().await?;
+    let mut failed = page.event_listener::<EventLoadingFailed>().await?;
+    let target = page.target_id().clone();
+
+    spawn(async move {
+        let mut limit = RateLimit::new(config.rate_limit);
+        let window = Duration::from_secs(config.exception_window);
+        let mut thrown: VecDeque<Instant> = VecDeque::new();
+        let mut urls: HashMap<RequestId, String> = HashMap::new();
+        loop {
+            select! {
+                Some(event) = console.next() => {
+                    let level = console_level(&event.r#type);
+                    if limit.allow() {
+                        let text = event.args.iter().map(describe).collect::<Vec<_>>().join(" ");
+                        let source = event.stack_trace.as_ref().map(top_frame);
+                        log(level, "console", &text, source.as_deref());
+                    }
+                }
+                Some(event) = exceptions.next() => {
+                    let details = &event.exception_details;
+                    if limit.allow() {
+                        let text = match details.exception.as_ref() {
+                            Some(exception) => describe(exception),
+                            None => details.text.clone(),
+                        };
+                        let source = match (&details.url, &details.stack_trace) {
+                            (Some(url), _) => Some(format!("{}:{}", url, details.line_number + 1)),
+                            (None, Some(stack_trace)) => Some(top_frame(stack_trace)),
+                            (None, None) => None,
+                        };
+                        log(Level::ERROR, "uncaught exception", &text, source.as_deref());
+                    }
+                    if config.reload_after_exceptions == 0 {
+                        continue;
+                    }
+                    let now = Instant::now();
+                    thrown.push_back(now);
+                    while thrown.front().is_some_and(|at| now - *at > window) {
+                        thrown.pop_front();
+                    }
+                    if thrown.len() >= config.reload_after_exceptions {
+                        warn!(
+                            target: "browser",
+                            "{} uncaught exceptions in {}s, reloading the page",
+                            thrown.len(),
+                            config.exception_window
+                        );
+                        thrown.clear();
+                        let failure = (Some(target.clone()), Failure::Exceptions);
+                        let _ = failure_tx.send(failure).await;
+                    }
+                }
+                Some(event) = entries.next() => {
+                    // failed requests are logged from the network events
+                    if event.entry.source == LogEntrySource::Network || !limit.allow() {
+                        continue;
+                    }
+                    let level = match event.entry.level {
+                        LogEntryLevel::Verbose => Level::DEBUG,
+                        LogEntryLevel::Info => Level::INFO,
+                        LogEntryLevel::Warning => Level::WARN,
+                        LogEntryLevel::Error => Level::ERROR,
+                    };
+                    let entry = &event.entry;
+                    let source = entry.url.as_ref().map(|url| match entry.line_number {
+                        Some(line) => format!("{}:{}", url, line + 1),
+                        None => url.clone(),
+                    });
+                    log(level, "browser", &entry.text, source.as_deref());
+                }
+                Some(event) = requests.next() => {
+                    if urls.len() < MAX_PENDING_REQUESTS {
+                        urls.insert(event.request_id.clone(), event.request.url.clone());
+                    }
+                }
+                Some(event) = responses.next() => {
+                    let status = event.response.status;
+                    if status >= 400 && limit.allow() {
+                        let text = format!("{} {}", status, event.response.status_text);
+                        log(Level::WARN, "request failed", &text, Some(&event.response.url));
+                    }
+                }
+                Some(event) = finished.next() => {
+                    urls.remove(&event.request_id);
+                }
+                Some(event) = failed.next() => {
+                    let url = urls.remove(&event.request_id);
+                    // navigating away cancels requests, that's not worth a warning
+                    let canceled = event.canceled == Some(true);
+                    let level = if canceled { Level::DEBUG } else { Level::WARN };
+                    if limit.allow() {
+                        log(level, "request failed", &event.error_text, url.as_deref());
+                    }
+                }
+                else => break,
+            }
+        }
+    });
+    Ok(())
+}
+
+fn log(level: Level, kind: &str, text: &str, source: Option<&str>) {
+    let source = source.unwrap_or("unknown source");
+    match level {
+        Level::ERROR => error!(target: "browser", "{}: {} ({})", kind, text, source),
+        Level::WARN => warn!(target: "browser", "{}: {} ({})", kind, text, source),
+        Level::INFO => info!(target: "browser", "{}: {} ({})", kind, text, source),
+        Level::DEBUG => debug!(target: "browser", "{}: {} ({})", kind, text, source),
+        Level::TRACE => trace!(target: "browser", "{}: {} ({})", kind, text, source),
+    }
+}
+
+fn console_level(kind: &ConsoleApiCalledType) -> Level {
+    match kind {
+        ConsoleApiCalledType::Error | ConsoleApiCalledType::Assert => Level::ERROR,
+        ConsoleApiCalledType::Warning => Level::WARN,
+        ConsoleApiCalledType::Debug => Level::DEBUG,
+        ConsoleApiCalledType::Trace => Level::TRACE,
+        _ => Level::INFO,
+    }
+}
+
+/// A console argument the way devtools would print it.
+fn describe(object: &RemoteObject) -> String {
+    if let Some(value) = &object.value {
+        return match value {
+            serde_json::Value::String(text) => text.clone(),
+            value => value.to_string(),
+        };
+    }
+    if let Some(value) = &object.unserializable_value {
+        return value.inner().clone();
+    }
+    object
+        .description
+        .clone()
+        .unwrap_or_else(|| format!("{:?}", object.r#type))
+}
+
+/// `url:line` of the innermost call frame.
+fn top_frame(stack_trace: &StackTrace) -> String {
+    match stack_trace.call_frames.first() {
+        Some(frame) => format!("{}:{}", frame.url, frame.line_number + 1),
+        None => "unknown source".to_string(),
+    }
+}
+
+/// Allows `limit` messages per second, logging how many were dropped with the
+/// first message of a later second.
+struct RateLimit {
+    limit: u32,
+    window_start: Instant,
+    count: u32,
+    suppressed: u64,
+}
+
+impl RateLimit {
+    fn new(limit: u32) -> Self {
+        RateLimit {
+            limit,
+            window_start: Instant::now(),
+            count: 0,
+            suppressed: 0,
+        }
+    }
+
+    fn allow(&mut self) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+        if self.window_start.elapsed() >= Duration::from_secs(1) {
+            if self.suppressed > 0 {
+                warn!(target: "browser", "suppressed {} noisy page messages", self.suppressed);
+            }
+            self.window_start = Instant::now();
+            self.count = 0;
+            self.suppressed = 0;
+        }
+        self.count += 1;
+        if self.count > self.limit {
+            self.suppressed += 1;
+            return false;
+        }
+        true
+    }
+}
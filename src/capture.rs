@@ -0,0 +1,185 @@
+mod screencast;
+mod test_pattern;
+mod x11;
+
+use chromiumoxide::Page;
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+    process::{ChildStderr, ChildStdout},
+    spawn,
+    sync::mpsc,
+    task::JoinHandle,
+    time::timeout,
+};
+use tokio_tungstenite::tungstenite::Bytes;
+use tracing::{debug, info, warn};
+
+use crate::browser_capture::CaptureConfig;
+use screencast::ScreencastBackend;
+pub use screencast::ScreencastConfig;
+pub use test_pattern::{TestPatternConfig, run_test_pattern};
+use x11::X11Backend;
+pub use x11::X11Config;
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the captured media comes from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// The extension records the tab with `tabCapture` and streams WebM over
+    /// its websocket.
+    #[default]
+    Extension,
+    /// DevTools screencast frames, muxed with audio from `screencast.audio`.
+    Screencast,
+    /// Chrome runs headful on an Xvfb display recorded with `x11grab`, its
+    /// sound comes from a PulseAudio null sink.
+    X11,
+    /// A generated test pattern and tone, without Chrome, see [`run_test_pattern`].
+    TestPattern,
+}
+
+/// Captures the tab in front and feeds its media to the encoder.
+pub trait CaptureBackend: Send + Sync {
+    /// Starts capturing `page`, which is ready and in front.
+    fn start<'a>(&'a mut self, page: &'a Page) -> BoxFuture<'a, ()>;
+
+    /// Stops capturing `page` before capture moves to another tab.
+    fn stop<'a>(&'a mut self, page: &'a Page) -> BoxFuture<'a, ()>;
+
+    /// Cleans up what the backend started, once Chrome is closed.
+    fn shutdown(&mut self) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
+
+    /// Environment variables Chrome is launched with.
+    fn browser_env(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    /// Extra Chrome arguments.
+    fn browser_args(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Whether Chrome has to run headful.
+    fn needs_window(&self) -> bool {
+        false
+    }
+}
+
+/// The backend selected in `config`, feeding the encoder through `stream_tx`.
+pub async fn new_backend(
+    config: &CaptureConfig,
+    ws_port: u16,
+    window_size: (u32, u32),
+    stream_tx: mpsc::Sender<Bytes>,
+) -> Box<dyn CaptureBackend> {
+    info!("capturing with the {:?} backend", config.backend);
+    match config.backend {
+        Backend::Extension => Box::new(ExtensionBackend { ws_port }),
+        Backend::Screencast => Box::new(ScreencastBackend::new(
+            config.screencast.clone(),
+            ws_port,
+            window_size,
+            stream_tx,
+        )),
+        Backend::X11 => {
+            let backend = X11Backend::new(config.x11.clone(), ws_port, window_size, stream_tx)
+                .await
+                .unwrap_or_else(|e| panic!("failed to start the virtual display: {}", e));
+            Box::new(backend)
+        }
+        Backend::TestPattern => unreachable!("the test pattern runs without a browser"),
+    }
+}
+
+/// Media arrives on the extension's websocket, which forwards it to the encoder.
+struct ExtensionBackend {
+    ws_port: u16,
+}
+
+impl CaptureBackend for ExtensionBackend {
+    fn start<'a>(&'a mut self, page: &'a Page) -> BoxFuture<'a, ()> {
+        Box::pin(send_start(page, self.ws_port, true))
+    }
+
+    fn stop<'a>(&'a mut self, page: &'a Page) -> BoxFuture<'a, ()> {
+        Box::pin(send_stop(page))
+    }
+}
+
+/// Connects the extension to the server, recording the tab if `record`.
+async fn send_start(page: &Page, ws_port: u16, record: bool) {
+    let start = page.evaluate(format!(
+        r#"
+            window.postMessage({{
+                type: 'CAPTURE_COMMAND',
+                command: 'start',
+                port: {},
+                record: {}
+            }}, '*');
+            console.log("[rs] sent start capture message");
+            "#,
+        ws_port, record
+    ));
+    match start.await {
+        Ok(_) => info!("sent start message"),
+        Err(e) => warn!("failed to send start message: {}", e),
+    }
+}
+
+/// Disconnects the extension, so only one tab streams at a time.
+async fn send_stop(page: &Page) {
+    let stop = page.evaluate(
+        r#"
+            window.postMessage({
+                type: 'CAPTURE_COMMAND',
+                command: 'stop'
+            }, '*');
+            "#,
+    );
+    match timeout(COMMAND_TIMEOUT, stop).await {
+        Ok(Ok(_)) => info!("sent stop message"),
+        Ok(Err(e)) => warn!("failed to send stop message: {}", e),
+        Err(_) => warn!("sending stop message timed out"),
+    }
+}
+
+/// Sends the media an ffmpeg writes to stdout to the encoder, until it exits.
+fn forward_output(mut stdout: ChildStdout, stream_tx: mpsc::Sender<Bytes>) -> JoinHandle<()> {
+    spawn(async move {
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            match stdout.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    let data = Bytes::copy_from_slice(&buffer[..n]);
+                    if stream_tx.send(data).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Stops a `forward_output` task and waits until it is gone, so none of an old
+/// capture's output reaches the encoder after the next capture's header.
+async fn stop_forwarding(forward: JoinHandle<()>) {
+    forward.abort();
+    let _ = forward.await;
+}
+
+fn log_stderr(stderr: ChildStderr, name: &'static str) {
+    spawn(async move {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            debug!("{}: {}", name, line);
+        }
+    });
+}
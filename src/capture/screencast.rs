@@ -0,0 +1,213 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chromiumoxide::{
+    Page,
+    cdp::browser_protocol::page::{
+        EventScreencastFrame, ScreencastFrameAckParams, StartScreencastFormat,
+        StartScreencastParams, StopScreencastParams,
+    },
+    error::CdpError,
+};
+use futures::{StreamExt, future::BoxFuture};
+use serde::Deserialize;
+use std::process::Stdio;
+use tokio::{
+    io::AsyncWriteExt,
+    process::{Child, Command},
+    spawn,
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tokio_tungstenite::tungstenite::Bytes;
+use tracing::{info, warn};
+
+use super::{CaptureBackend, forward_output, log_stderr, send_start, send_stop, stop_forwarding};
+use crate::metrics;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ScreencastConfig {
+    /// JPEG quality of the frames, 0 to 100.
+    pub quality: u8,
+    /// PulseAudio source to record sound from, silence when unset.
+    pub audio: Option<String>,
+}
+
+impl Default for ScreencastConfig {
+    fn default() -> Self {
+        ScreencastConfig {
+            quality: 80,
+            audio: None,
+        }
+    }
+}
+
+/// Captures with the DevTools `Page.startScreencast`, so no extension has to
+/// load. Each capture runs an ffmpeg that muxes the JPEG frames with the
+/// audio into Matroska, which the encoder splices in like the extension's
+/// WebM. The extension still connects for page messages if it is there.
+pub struct ScreencastBackend {
+    config: ScreencastConfig,
+    ws_port: u16,
+    window_size: (u32, u32),
+    stream_tx: mpsc::Sender<Bytes>,
+    session: Option<Session>,
+}
+
+/// A running screencast: the task feeding frames to the muxer, which owns it,
+/// and the task forwarding the muxer's output.
+struct Session {
+    frames: JoinHandle<()>,
+    forward: JoinHandle<()>,
+}
+
+impl ScreencastBackend {
+    pub fn new(
+        config: ScreencastConfig,
+        ws_port: u16,
+        window_size: (u32, u32),
+        stream_tx: mpsc::Sender<Bytes>,
+    ) -> Self {
+        ScreencastBackend {
+            config,
+            ws_port,
+            window_size,
+            stream_tx,
+            session: None,
+        }
+    }
+
+    async fn start_session(&self, page: &Page) -> Result<Session, CdpError> {
+        let mut frames = page.event_listener::<EventScreencastFrame>().await?;
+        let mut muxer = self.spawn_muxer()?;
+        let mut stdin = muxer.stdin.take().unwrap();
+        let forward = forward_output(muxer.stdout.take().unwrap(), self.stream_tx.clone());
+        log_stderr(muxer.stderr.take().unwrap(), "screencast ffmpeg");
+
+        let params = StartScreencastParams::builder()
+            .format(StartScreencastFormat::Jpeg)
+            .quality(self.config.quality as i64)
+            .max_width(self.window_size.0 as i64)
+            .max_height(self.window_size.1 as i64)
+            .build();
+        page.execute(params).await?;
+
+        let page = page.clone();
+        let frames = spawn(async move {
+            // dropped with the task, which kills it
+            let _muxer = muxer;
+            while let Some(frame) = frames.next().await {
+                // chrome sends the next frame once this one is acknowledged
+                let ack = ScreencastFrameAckParams::new(frame.session_id);
+                if let Err(e) = page.execute(ack).await {
+                    warn!("failed to acknowledge screencast frame: {}", e);
+                    break;
+                }
+                let data: &str = frame.data.as_ref();
+                let jpeg = match STANDARD.decode(data) {
+                    Ok(jpeg) => jpeg,
+                    Err(e) => {
+                        warn!("invalid screencast frame: {}", e);
+                        continue;
+                    }
+                };
+                metrics::inc("webstreamer_screencast_frames_total", &[]);
+                if let Err(e) = stdin.write_all(&jpeg).await {
+                    warn!("screencast ffmpeg stopped taking frames: {}", e);
+                    break;
+                }
+            }
+            info!("screencast ended");
+        });
+        Ok(Session { frames, forward })
+    }
+
+    fn spawn_muxer(&self) -> std::io::Result<Child> {
+        let mut command = Command::new("ffmpeg");
+        command.args([
+            "-use_wallclock_as_timestamps",
+            "1",
+            "-f",
+            "image2pipe",
+            "-c:v",
+            "mjpeg",
+            "-i",
+            "-",
+        ]);
+        match &self.config.audio {
+            Some(source) => command.args(["-f", "pulse", "-i", source]),
+            None => command.args(["-f", "lavfi", "-i", "anullsrc=r=48000:cl=stereo"]),
+        };
+        command
+            .args([
+                "-map",
+                "0:v",
+                "-map",
+                "1:a",
+                "-c:v",
+                "copy",
+                "-c:a",
+                "pcm_s16le",
+                "-flush_packets",
+                "1",
+                "-live",
+                "1",
+                "-f",
+                "matroska",
+                "-",
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+    }
+
+    /// Kills the muxer and waits for its output to stop, before the next
+    /// session's muxer writes its header.
+    async fn end_session(&mut self) {
+        if let Some(Session { frames, forward }) = self.session.take() {
+            frames.abort();
+            let _ = frames.await;
+            stop_forwarding(forward).await;
+        }
+    }
+}
+
+impl CaptureBackend for ScreencastBackend {
+    fn start<'a>(&'a mut self, page: &'a Page) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            self.end_session().await;
+            send_start(page, self.ws_port, false).await;
+            match self.start_session(page).await {
+                Ok(session) => {
+                    info!("started screencast");
+                    self.session = Some(session);
+                }
+                Err(e) => warn!("failed to start screencast: {}", e),
+            }
+        })
+    }
+
+    fn stop<'a>(&'a mut self, page: &'a Page) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            if let Err(e) = page.execute(StopScreencastParams::default()).await {
+                warn!("failed to stop screencast: {}", e);
+            }
+            self.end_session().await;
+            send_stop(page).await;
+        })
+    }
+
+    fn shutdown(&mut self) -> BoxFuture<'_, ()> {
+        Box::pin(self.end_session())
+    }
+}
+
+impl Drop for ScreencastBackend {
+    fn drop(&mut self) {
+        if let Some(session) = self.session.take() {
+            session.frames.abort();
+            session.forward.abort();
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use serde::Deserialize;
+use std::{io, process::Stdio, time::Duration};
+use tokio::{
+    process::{Child, Command},
+    select, spawn,
+    sync::mpsc,
+    task::JoinHandle,
+    time::sleep,
+};
+use tokio_tungstenite::tungstenite::Bytes;
+use tracing::{debug, info, warn};
+
+use super::{forward_output, log_stderr, stop_forwarding};
+use crate::{control::Control, status::Status};
+
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TestPatternConfig {
+    /// Frequency of the tone, in Hz.
+    pub frequency: u32,
+}
+
+impl Default for TestPatternConfig {
+    fn default() -> Self {
+        TestPatternConfig { frequency: 440 }
+    }
+}
+
+/// Streams ffmpeg's `testsrc2` pattern and a `sine` tone as VP8 and Opus
+/// WebM, the same shape as the extension's MediaRecorder output, without
+/// Chrome. Reloads, navigations and scene switches restart the generator, so
+/// the encoder splices in a new capture just like it does for the browser.
+pub fn run_test_pattern(
+    config: TestPatternConfig,
+    window_size: (u32, u32),
+    stream_tx: mpsc::Sender<Bytes>,
+    mut control_rx: mpsc::Receiver<Control>,
+    status: Status,
+) -> JoinHandle<()> {
+    spawn(async move {
+        status.update(|s| {
+            s.browser.state = "test-pattern".to_string();
+            s.browser.url = String::new();
+        });
+        let mut generator = None;
+        loop {
+            if generator.is_none() {
+                match spawn_generator(&config, window_size, &stream_tx) {
+                    Ok(started) => {
+                        info!("started test pattern");
+                        generator = Some(started);
+                    }
+                    Err(e) => warn!("failed to start test pattern: {}", e),
+                }
+            }
+            let exited = async {
+                match generator.as_mut() {
+                    Some(generator) => Some(generator.child.wait().await),
+                    None => {
+                        // failed to start, retried on the next turn
+                        sleep(RESTART_DELAY).await;
+                        None
+                    }
+                }
+            };
+            select! {
+                result = exited => {
+                    if let Some(result) = result {
+                        warn!("test pattern stopped: {:?}", result);
+                        stop(generator.take()).await;
+                        sleep(RESTART_DELAY).await;
+                    }
+                }
+                control = control_rx.recv() => {
+                    let Some(control) = control else {
+                        break;
+                    };
+                    info!("control: {:?}", control);
+                    match control {
+                        Control::Reload | Control::Navigate(_) => stop(generator.take()).await,
+                        Control::SwitchScene(scene) => {
+                            status.set_scene(&scene);
+                            stop(generator.take()).await;
+                        }
+                        Control::Screenshot(tx) => {
+                            let _ = tx.send(None);
+                        }
+                        Control::Input(_) | Control::SiteChanged => {
+                            debug!("ignored without a browser");
+                        }
+                        Control::Shutdown => break,
+                    }
+                }
+            }
+        }
+        stop(generator).await;
+    })
+}
+
+/// A running ffmpeg and the task forwarding its output to the encoder.
+struct Generator {
+    child: Child,
+    forward: JoinHandle<()>,
+}
+
+/// Kills the generator and waits for its forwarder to finish.
+async fn stop(generator: Option<Generator>) {
+    let Some(Generator { child, forward }) = generator else {
+        return;
+    };
+    drop(child);
+    stop_forwarding(forward).await;
+}
+
+fn spawn_generator(
+    config: &TestPatternConfig,
+    window_size: (u32, u32),
+    stream_tx: &mpsc::Sender<Bytes>,
+) -> io::Result<Generator> {
+    let mut generator = Command::new("ffmpeg")
+        .args([
+            "-re",
+            "-f",
+            "lavfi",
+            "-i",
+            &format!("testsrc2=size={}x{}:rate=60", window_size.0, window_size.1),
+            "-f",
+            "lavfi",
+            "-i",
+            &format!("sine=frequency={}:sample_rate=48000", config.frequency),
+            "-map",
+            "0:v",
+            "-map",
+            "1:a",
+            "-c:v",
+            "libvpx",
+            "-deadline",
+            "realtime",
+            "-cpu-used",
+            "8",
+            "-b:v",
+            "2500k",
+            "-c:a",
+            "libopus",
+            "-b:a",
+            "128k",
+            "-ac",
+            "2",
+            "-flush_packets",
+            "1",
+            "-live",
+            "1",
+            "-f",
+            "webm",
+            "-",
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let forward = forward_output(generator.stdout.take().unwrap(), stream_tx.clone());
+    log_stderr(generator.stderr.take().unwrap(), "test pattern ffmpeg");
+    Ok(Generator {
+        child: generator,
+        forward,
+    })
+}
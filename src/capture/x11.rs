@@ -0,0 +1,325 @@
+use chromiumoxide::Page;
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use std::{fs, io, path::Path, process::Stdio, time::Duration};
+use tokio::{
+    process::{Child, Command},
+    sync::mpsc,
+    task::JoinHandle,
+    time::{Instant, sleep},
+};
+use tokio_tungstenite::tungstenite::Bytes;
+use tracing::{info, warn};
+
+use super::{CaptureBackend, forward_output, log_stderr, send_start, send_stop, stop_forwarding};
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+const STARTUP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct X11Config {
+    /// Xvfb display number, `:99` by default.
+    pub display: u32,
+    /// Name of the PulseAudio null sink Chrome plays into.
+    pub sink: String,
+}
+
+impl Default for X11Config {
+    fn default() -> Self {
+        X11Config {
+            display: 99,
+            sink: "webstreamer".to_string(),
+        }
+    }
+}
+
+/// An Xvfb display and a PulseAudio null sink for Chrome to run headful in,
+/// removed again by [`VirtualDisplay::stop`]. Dropping it only kills the
+/// processes it started.
+struct VirtualDisplay {
+    xvfb: Child,
+    /// Only set when no PulseAudio server was running yet.
+    pulseaudio: Option<Child>,
+    /// Module index of the null sink.
+    sink_module: Option<String>,
+}
+
+impl VirtualDisplay {
+    async fn start(config: &X11Config, window_size: (u32, u32)) -> io::Result<Self> {
+        let name = format!(":{}", config.display);
+        clear_stale_display(config.display)?;
+        info!("starting Xvfb on display {}", name);
+        let mut xvfb = Command::new("Xvfb")
+            .args([
+                name.as_str(),
+                "-screen",
+                "0",
+                &format!("{}x{}x24", window_size.0, window_size.1),
+                "-nolisten",
+                "tcp",
+            ])
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        log_stderr(xvfb.stderr.take().unwrap(), "Xvfb");
+        let socket = format!("/tmp/.X11-unix/X{}", config.display);
+        wait_for("Xvfb", &mut xvfb, || async { Path::new(&socket).exists() }).await?;
+
+        let pulseaudio = if pulse_running().await {
+            None
+        } else {
+            info!("starting PulseAudio");
+            let mut pulseaudio = Command::new("pulseaudio")
+                .args(["--daemonize=no", "--exit-idle-time=-1"])
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()?;
+            log_stderr(pulseaudio.stderr.take().unwrap(), "pulseaudio");
+            wait_for("PulseAudio", &mut pulseaudio, pulse_running).await?;
+            Some(pulseaudio)
+        };
+        let mut display = VirtualDisplay {
+            xvfb,
+            pulseaudio,
+            sink_module: None,
+        };
+
+        let output = Command::new("pactl")
+            .args([
+                "load-module",
+                "module-null-sink",
+                &format!("sink_name={}", config.sink),
+            ])
+            .output()
+            .await?;
+        if !output.status.success() {
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(io::Error::other(format!(
+                "failed to create sink: {}",
+                error
+            )));
+        }
+        display.sink_module = Some(String::from_utf8_lossy(&output.stdout).trim().to_string());
+        info!("created PulseAudio sink {}", config.sink);
+        Ok(display)
+    }
+
+    async fn stop(&mut self) {
+        // a PulseAudio server we started takes the sink down with it
+        if self.pulseaudio.is_none()
+            && let Some(module) = self.sink_module.take()
+        {
+            let unloaded = Command::new("pactl")
+                .args(["unload-module", &module])
+                .status()
+                .await;
+            if !matches!(unloaded, Ok(status) if status.success()) {
+                warn!("failed to remove PulseAudio sink module {}", module);
+            }
+        }
+        info!("stopping Xvfb");
+        let _ = self.xvfb.kill().await;
+        if let Some(pulseaudio) = self.pulseaudio.as_mut() {
+            let _ = pulseaudio.kill().await;
+        }
+    }
+}
+
+async fn pulse_running() -> bool {
+    let info = Command::new("pactl")
+        .arg("info")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await;
+    matches!(info, Ok(status) if status.success())
+}
+
+/// Removes the lock file and socket an Xvfb that didn't exit cleanly left
+/// behind, which would make the new one fail or look ready before it is.
+/// Fails if the display's lock is held by a running process.
+fn clear_stale_display(display: u32) -> io::Result<()> {
+    let lock = format!("/tmp/.X{}-lock", display);
+    let socket = format!("/tmp/.X11-unix/X{}", display);
+    if let Ok(pid) = fs::read_to_string(&lock)
+        && let Ok(pid) = pid.trim().parse::<u32>()
+        && Path::new(&format!("/proc/{}", pid)).exists()
+    {
+        return Err(io::Error::other(format!(
+            "display :{} is in use by process {}",
+            display, pid
+        )));
+    }
+    for path in [&lock, &socket] {
+        match fs::remove_file(path) {
+            Ok(()) => info!("removed stale {}", path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Waits for `ready`, failing early if `child` exits first.
+async fn wait_for<F, Fut>(name: &str, child: &mut Child, mut ready: F) -> io::Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while !ready().await {
+        if let Some(status) = child.try_wait()? {
+            return Err(io::Error::other(format!("{} exited: {}", name, status)));
+        }
+        if Instant::now() >= deadline {
+            return Err(io::Error::other(format!("{} did not start", name)));
+        }
+        sleep(STARTUP_POLL_INTERVAL).await;
+    }
+    Ok(())
+}
+
+/// Runs Chrome headful on its own Xvfb display and records the display with
+/// `x11grab` and the sink with `pulse`. The recording keeps running across
+/// reloads and scene switches, the display always shows the tab in front.
+pub struct X11Backend {
+    config: X11Config,
+    ws_port: u16,
+    window_size: (u32, u32),
+    stream_tx: mpsc::Sender<Bytes>,
+    recorder: Option<Child>,
+    /// Forwards the recorder's output, stopped before a new recorder starts.
+    forward: Option<JoinHandle<()>>,
+    // dropped last, after the recorder
+    display: VirtualDisplay,
+}
+
+impl X11Backend {
+    pub async fn new(
+        config: X11Config,
+        ws_port: u16,
+        window_size: (u32, u32),
+        stream_tx: mpsc::Sender<Bytes>,
+    ) -> io::Result<Self> {
+        let display = VirtualDisplay::start(&config, window_size).await?;
+        Ok(X11Backend {
+            config,
+            ws_port,
+            window_size,
+            stream_tx,
+            recorder: None,
+            forward: None,
+            display,
+        })
+    }
+
+    fn spawn_recorder(&self) -> io::Result<(Child, JoinHandle<()>)> {
+        let mut recorder = Command::new("ffmpeg")
+            .args([
+                "-f",
+                "x11grab",
+                "-video_size",
+                &format!("{}x{}", self.window_size.0, self.window_size.1),
+                "-framerate",
+                "60",
+                "-draw_mouse",
+                "0",
+                "-i",
+                &format!(":{}", self.config.display),
+                "-f",
+                "pulse",
+                "-i",
+                &format!("{}.monitor", self.config.sink),
+                "-map",
+                "0:v",
+                "-map",
+                "1:a",
+                "-c:v",
+                "mjpeg",
+                "-q:v",
+                "3",
+                "-pix_fmt",
+                "yuvj420p",
+                "-c:a",
+                "pcm_s16le",
+                "-flush_packets",
+                "1",
+                "-live",
+                "1",
+                "-f",
+                "matroska",
+                "-",
+            ])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let forward = forward_output(recorder.stdout.take().unwrap(), self.stream_tx.clone());
+        log_stderr(recorder.stderr.take().unwrap(), "x11 ffmpeg");
+        Ok((recorder, forward))
+    }
+
+    fn recording(&mut self) -> bool {
+        self.recorder
+            .as_mut()
+            .is_some_and(|recorder| matches!(recorder.try_wait(), Ok(None)))
+    }
+}
+
+impl CaptureBackend for X11Backend {
+    fn start<'a>(&'a mut self, page: &'a Page) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            send_start(page, self.ws_port, false).await;
+            if self.recording() {
+                return;
+            }
+            if let Some(forward) = self.forward.take() {
+                stop_forwarding(forward).await;
+            }
+            match self.spawn_recorder() {
+                Ok((recorder, forward)) => {
+                    info!("recording display :{}", self.config.display);
+                    self.recorder = Some(recorder);
+                    self.forward = Some(forward);
+                }
+                Err(e) => warn!("failed to start recording the display: {}", e),
+            }
+        })
+    }
+
+    fn stop<'a>(&'a mut self, page: &'a Page) -> BoxFuture<'a, ()> {
+        Box::pin(send_stop(page))
+    }
+
+    fn shutdown(&mut self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            if let Some(mut recorder) = self.recorder.take() {
+                let _ = recorder.kill().await;
+            }
+            if let Some(forward) = self.forward.take() {
+                stop_forwarding(forward).await;
+            }
+            self.display.stop().await;
+        })
+    }
+
+    fn browser_env(&self) -> Vec<(String, String)> {
+        vec![
+            ("DISPLAY".to_string(), format!(":{}", self.config.display)),
+            ("PULSE_SINK".to_string(), self.config.sink.clone()),
+        ]
+    }
+
+    fn browser_args(&self) -> Vec<String> {
+        // fills the display without tabs or an address bar
+        vec!["--kiosk".to_string(), "--window-position=0,0".to_string()]
+    }
+
+    fn needs_window(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,62 @@
+use serde::Deserialize;
+use std::{env, fs, path::Path};
+use tracing::info;
+
+use crate::{
+    admin::AdminConfig,
+    browser_capture::CaptureConfig,
+    page::BacklogConfig,
+    plays::PlaysConfig,
+    scenes::ScenesConfig,
+    schedule::ScheduleConfig,
+    site::SiteConfig,
+    twitch::{
+        AlertsConfig, ChatConfig, CommandsConfig, MetadataConfig, ModerationConfig, OrderingConfig,
+        PollsConfig, RewardsConfig,
+    },
+};
+
+/// Optional JSON config file, read from `CONFIG` (defaults to `webstreamer.json`).
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Admin API and dashboard, enabled by setting `ADMIN_TOKEN`.
+    pub admin: AdminConfig,
+    pub alerts: AlertsConfig,
+    /// Logins of other channels to watch, events from them are tagged with `channel`.
+    pub channels: Vec<String>,
+    pub backlog: BacklogConfig,
+    /// How pages are captured and when capture starts.
+    pub capture: CaptureConfig,
+    pub chat: ChatConfig,
+    pub commands: CommandsConfig,
+    /// Channel title, category and tags.
+    pub metadata: MetadataConfig,
+    pub moderation: ModerationConfig,
+    /// How twitch notifications are put in timestamp order.
+    pub ordering: OrderingConfig,
+    /// Chat controlled browser input.
+    pub plays: PlaysConfig,
+    pub polls: PollsConfig,
+    /// Channel point rewards managed by the server.
+    pub rewards: RewardsConfig,
+    /// Scenes preloaded in their own tabs and how to switch between them.
+    pub scenes: ScenesConfig,
+    /// Urls to navigate to at set times.
+    pub schedule: ScheduleConfig,
+    /// Local directory served as the site, reloaded when it changes.
+    pub site: SiteConfig,
+}
+
+impl Config {
+    pub fn load() -> Self {
+        let path = env::var("CONFIG").unwrap_or("webstreamer.json".to_string());
+        if !Path::new(&path).exists() {
+            info!("no config file at {}, using defaults", path);
+            return Config::default();
+        }
+        info!("loading config from {}", path);
+        let contents = fs::read_to_string(&path).unwrap();
+        serde_json::from_str(&contents).unwrap()
+    }
+}
@@ -0,0 +1,22 @@
+use tokio::sync::oneshot;
+
+use crate::plays::InputAction;
+
+/// Actions on the browser and stream requested by chat commands and other
+/// parts of the server, handled by the browser task in `main`.
+#[derive(Debug)]
+pub enum Control {
+    Reload,
+    /// Load another url and restart capture there.
+    Navigate(String),
+    SwitchScene(String),
+    /// Keyboard or mouse input for the captured tab.
+    Input(InputAction),
+    /// PNG of the tab, `None` if it couldn't be taken.
+    Screenshot(oneshot::Sender<Option<Vec<u8>>>),
+    /// Files of the served site changed, see `site.rs`.
+    SiteChanged,
+    /// The process is exiting, close the browser and clean up the capture
+    /// backend.
+    Shutdown,
+}
@@ -0,0 +1,610 @@
+use std::{process::Stdio, time::Instant};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::{Child, ChildStdin, Command},
+    select, spawn,
+    sync::mpsc::Receiver,
+};
+use tokio_tungstenite::tungstenite::Bytes;
+use tracing::{info, warn};
+
+use crate::{metrics, status::Status};
+
+const EBML_MAGIC: [u8; 4] = [0x1a, 0x45, 0xdf, 0xa3];
+const CLUSTER_ID: [u8; 4] = [0x1f, 0x43, 0xb6, 0x75];
+const TIMECODE_ID: u8 = 0xe7;
+/// The value bits of an 8 byte EBML size, all set for an unknown size.
+const UNKNOWN_SIZE: u64 = 0x00ff_ffff_ffff_ffff;
+
+#[derive(Debug)]
+pub enum EncoderControl {
+    /// Starts the RTMP output if it is stopped.
+    Start,
+    /// Stops ffmpeg and drops captured media until started again.
+    Stop,
+    Restart,
+}
+
+struct Ffmpeg {
+    child: Child,
+    stdin: ChildStdin,
+}
+
+/// The WebM header of the current capture, everything before the first
+/// cluster, so a (re)started ffmpeg can join mid-stream.
+#[derive(Default)]
+struct WebmHeader {
+    bytes: Vec<u8>,
+    complete: bool,
+}
+
+impl WebmHeader {
+    fn observe(&mut self, data: &[u8]) {
+        if data.starts_with(&EBML_MAGIC) {
+            self.bytes.clear();
+            self.complete = false;
+        }
+        if self.complete {
+            return;
+        }
+        match find(data, &CLUSTER_ID) {
+            Some(index) => {
+                self.bytes.extend_from_slice(&data[..index]);
+                self.complete = true;
+            }
+            None => self.bytes.extend_from_slice(data),
+        }
+    }
+}
+
+/// How captured data reaches the running ffmpeg.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Feed {
+    /// A new ffmpeg needs the header first, then data from a cluster boundary.
+    Joining,
+    /// The capture restarted (reload, navigation, recovery), its header is
+    /// skipped up to the first cluster since ffmpeg already has one.
+    Splicing,
+    Joined,
+}
+
+/// Shifts cluster timecodes so a capture that restarts at 0 continues the
+/// timeline ffmpeg is already encoding, and ffmpeg never sees time go back.
+#[derive(Default)]
+struct Timeline {
+    /// Milliseconds added to every cluster timecode of the current capture.
+    offset: i64,
+    /// The offset is picked again at the next cluster.
+    resync: bool,
+    /// The last cluster timecode written and when.
+    last: Option<(u64, Instant)>,
+    /// A cluster header split across chunks, held back until complete.
+    pending: Vec<u8>,
+}
+
+enum ClusterHeader {
+    Incomplete,
+    /// A false match of the cluster id inside frame data.
+    Invalid,
+    Valid {
+        /// `None` for the unknown size live WebM uses.
+        size: Option<u64>,
+        timecode: u64,
+        /// Length of the id, size and timecode element.
+        len: usize,
+        /// Length of the timecode element alone.
+        timecode_len: usize,
+    },
+}
+
+impl Timeline {
+    /// The next clusters belong to a new capture.
+    fn splice(&mut self) {
+        self.resync = true;
+        self.pending.clear();
+    }
+
+    /// Returns `data` with its cluster timecodes shifted.
+    fn rewrite(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut input = std::mem::take(&mut self.pending);
+        input.extend_from_slice(data);
+        let mut out = Vec::with_capacity(input.len() + 16);
+        let mut pos = 0;
+        while let Some(index) = find(&input[pos..], &CLUSTER_ID).map(|i| pos + i) {
+            out.extend_from_slice(&input[pos..index]);
+            match parse_cluster_header(&input[index..]) {
+                ClusterHeader::Incomplete => {
+                    self.pending = input[index..].to_vec();
+                    return out;
+                }
+                ClusterHeader::Invalid => {
+                    out.extend_from_slice(&CLUSTER_ID);
+                    pos = index + CLUSTER_ID.len();
+                }
+                ClusterHeader::Valid {
+                    size,
+                    timecode,
+                    len,
+                    timecode_len,
+                } => {
+                    // the timecode is always written as 8 bytes, 10 with its id and size
+                    let size = size.map_or(UNKNOWN_SIZE, |size| size + 10 - timecode_len as u64);
+                    out.extend_from_slice(&CLUSTER_ID);
+                    out.push(0x01);
+                    out.extend_from_slice(&size.to_be_bytes()[1..]);
+                    out.extend_from_slice(&[TIMECODE_ID, 0x88]);
+                    out.extend_from_slice(&self.shift(timecode).to_be_bytes());
+                    pos = index + len;
+                }
+            }
+        }
+        // the end may be the start of a cluster id
+        let keep = (1..CLUSTER_ID.len())
+            .rev()
+            .find(|&n| input.len() - pos >= n && input.ends_with(&CLUSTER_ID[..n]))
+            .unwrap_or(0);
+        out.extend_from_slice(&input[pos..input.len() - keep]);
+        self.pending = input[input.len() - keep..].to_vec();
+        out
+    }
+
+    fn shift(&mut self, timecode: u64) -> u64 {
+        if self.resync {
+            // continue from where the previous capture left off, in wall clock time
+            let now = self
+                .last
+                .map_or(0, |(last, at)| last + at.elapsed().as_millis() as u64);
+            self.offset = now as i64 - timecode as i64;
+            self.resync = false;
+        }
+        let timecode = (timecode as i64 + self.offset).max(0) as u64;
+        self.last = Some((timecode, Instant::now()));
+        timecode
+    }
+}
+
+/// Encodes the captured WebM to `rtmp_url` with ffmpeg, which can be
+/// restarted, stopped and started through `control_rx` without the capture
+/// noticing. A capture that restarts is spliced into the running ffmpeg, so
+/// the broadcast survives reloads and navigations.
+pub async fn run_encoder(
+    rtmp_url: String,
+    mut stream_rx: Receiver<Bytes>,
+    mut control_rx: Receiver<EncoderControl>,
+    status: Status,
+) {
+    let mut header = WebmHeader::default();
+    let mut ffmpeg: Option<Ffmpeg> = None;
+    let mut output = true;
+    let mut feed = Feed::Joining;
+    let mut timeline = Timeline::default();
+    status.update(|s| s.encoder.output = output);
+
+    loop {
+        select! {
+            data = stream_rx.recv() => {
+                let Some(data) = data else {
+                    break;
+                };
+                header.observe(&data);
+                if !output {
+                    let labels = [("reason", "output_stopped")];
+                    metrics::inc("webstreamer_stream_dropped_chunks_total", &labels);
+                    continue;
+                }
+                if ffmpeg.is_none() {
+                    ffmpeg = Some(start_ffmpeg(&rtmp_url, &status));
+                    feed = Feed::Joining;
+                    timeline = Timeline::default();
+                }
+                if feed == Feed::Joined && data.starts_with(&EBML_MAGIC) {
+                    info!("capture restarted, splicing it into the running ffmpeg");
+                    feed = Feed::Splicing;
+                    timeline.splice();
+                    status.update(|s| s.encoder.splices += 1);
+                }
+                let mut out = Vec::new();
+                let start = match feed {
+                    Feed::Joined => Some(0),
+                    Feed::Joining if data.starts_with(&EBML_MAGIC) => Some(0),
+                    Feed::Joining if header.complete => find(&data, &CLUSTER_ID)
+                        .inspect(|_| out.extend_from_slice(&header.bytes)),
+                    Feed::Joining => None,
+                    Feed::Splicing => find(&data, &CLUSTER_ID),
+                };
+                let process = ffmpeg.as_mut().unwrap();
+                let result = match start {
+                    Some(index) => {
+                        feed = Feed::Joined;
+                        out.extend(timeline.rewrite(&data[index..]));
+                        process.stdin.write_all(&out).await
+                    }
+                    None => {
+                        let reason = if feed == Feed::Splicing { "splicing" } else { "joining" };
+                        let labels = [("reason", reason)];
+                        metrics::inc("webstreamer_stream_dropped_chunks_total", &labels);
+                        Ok(())
+                    }
+                };
+                if let Err(e) = result {
+                    warn!("ffmpeg stopped taking input: {}", e);
+                    stop_ffmpeg(ffmpeg.take().unwrap(), &status).await;
+                    metrics::inc("webstreamer_encoder_restarts_total", &[("reason", "exited")]);
+                }
+            }
+            Some(control) = control_rx.recv() => {
+                info!("encoder control: {:?}", control);
+                match control {
+                    EncoderControl::Start => output = true,
+                    EncoderControl::Stop => {
+                        output = false;
+                        if let Some(process) = ffmpeg.take() {
+                            stop_ffmpeg(process, &status).await;
+                        }
+                    }
+                    EncoderControl::Restart => {
+                        if let Some(process) = ffmpeg.take() {
+                            stop_ffmpeg(process, &status).await;
+                        }
+                        status.update(|s| s.encoder.restarts += 1);
+                        let labels = [("reason", "requested")];
+                        metrics::inc("webstreamer_encoder_restarts_total", &labels);
+                    }
+                }
+                status.update(|s| s.encoder.output = output);
+            }
+        }
+    }
+    if let Some(process) = ffmpeg.take() {
+        stop_ffmpeg(process, &status).await;
+    }
+}
+
+fn start_ffmpeg(rtmp_url: &str, status: &Status) -> Ffmpeg {
+    info!("starting ffmpeg process");
+    let mut child = Command::new("ffmpeg")
+        .args([
+            "-i",
+            "-",
+            "-vsync",
+            "cfr",
+            "-c:v",
+            "h264_nvenc",
+            "-rc",
+            "cbr",
+            "-r",
+            "60",
+            "-s",
+            "1280x720",
+            "-b:v",
+            "4500k",
+            "-maxrate",
+            "4500k",
+            "-bufsize",
+            "9000k",
+            "-pix_fmt",
+            "yuv420p",
+            "-profile:v",
+            "high",
+            "-c:a",
+            "aac",
+            "-b:a",
+            "160k",
+            "-ar",
+            "48000",
+            "-ac",
+            "2",
+            // no frames come in while a restarted capture loads, once it is
+            // spliced in its timecodes jump ahead and the gap is filled with
+            // silence here and copies of the last frame by `-vsync cfr`
+            "-af",
+            "aresample=async=1",
+            "-progress",
+            "pipe:1",
+            "-nostats",
+            "-f",
+            "flv",
+            rtmp_url,
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+
+    let stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+
+    spawn(async move {
+        let mut lines = BufReader::new(stderr).lines();
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) => info!("ffmpeg: {}", line),
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Error reading ffmpeg stderr: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+    let progress_status = status.clone();
+    spawn(async move {
+        let mut lines = BufReader::new(stdout).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if let Some((key, value)) = line.split_once('=') {
+                record_progress(key.trim(), value.trim());
+                progress_status.update(|s| {
+                    s.encoder
+                        .progress
+                        .insert(key.trim().to_string(), value.trim().to_string());
+                });
+            }
+        }
+    });
+    status.update(|s| {
+        s.encoder.running = true;
+        s.encoder.progress.clear();
+    });
+    metrics::set("webstreamer_encoder_running", &[], 1.0);
+    Ffmpeg { child, stdin }
+}
+
+async fn stop_ffmpeg(mut ffmpeg: Ffmpeg, status: &Status) {
+    info!("stopping ffmpeg process");
+    // closing stdin lets ffmpeg flush and end the rtmp stream cleanly
+    drop(ffmpeg.stdin);
+    match ffmpeg.child.wait().await {
+        Ok(status) => info!("ffmpeg process exited with status: {}", status),
+        Err(e) => warn!("failed to wait for ffmpeg process: {}", e),
+    }
+    status.update(|s| s.encoder.running = false);
+    metrics::set("webstreamer_encoder_running", &[], 0.0);
+}
+
+/// Exports ffmpeg's `fps`, `bitrate` (like `4500.2kbits/s`) and `speed` (like
+/// `1.01x`) progress values, which are `N/A` until the first frames are out.
+fn record_progress(key: &str, value: &str) {
+    let (name, value) = match key {
+        "fps" => ("webstreamer_encoder_fps", value),
+        "bitrate" => (
+            "webstreamer_encoder_bitrate_kbps",
+            value.trim_end_matches("kbits/s"),
+        ),
+        "speed" => ("webstreamer_encoder_speed", value.trim_end_matches('x')),
+        _ => return,
+    };
+    if let Ok(value) = value.trim().parse() {
+        metrics::set(name, &[], value);
+    }
+}
+
+/// Parses a cluster's id, size and the timecode element that starts it.
+fn parse_cluster_header(data: &[u8]) -> ClusterHeader {
+    let mut pos = CLUSTER_ID.len();
+    let Some((size_len, size)) = read_vint(&data[pos..]) else {
+        return ClusterHeader::Incomplete;
+    };
+    pos += size_len;
+    let Some(&id) = data.get(pos) else {
+        return ClusterHeader::Incomplete;
+    };
+    if id != TIMECODE_ID {
+        return ClusterHeader::Invalid;
+    }
+    let Some((value_len_len, value_len)) = read_vint(&data[pos + 1..]) else {
+        return ClusterHeader::Incomplete;
+    };
+    let value_len = value_len as usize;
+    if value_len > 8 {
+        return ClusterHeader::Invalid;
+    }
+    let value_start = pos + 1 + value_len_len;
+    let Some(value) = data.get(value_start..value_start + value_len) else {
+        return ClusterHeader::Incomplete;
+    };
+    let timecode = value.iter().fold(0, |acc, &b| acc << 8 | b as u64);
+    // an unknown size has all value bits set
+    let unknown = size == (1 << (7 * size_len)) - 1;
+    ClusterHeader::Valid {
+        size: (!unknown).then_some(size),
+        timecode,
+        len: value_start + value_len,
+        timecode_len: value_start + value_len - pos,
+    }
+}
+
+/// Reads an EBML variable size integer, returning its length and value, or
+/// `None` if `data` ends first. A zero first byte is read as the longest
+/// length so false matches fail later instead.
+fn read_vint(data: &[u8]) -> Option<(usize, u64)> {
+    let first = *data.first()?;
+    let len = (first.leading_zeros() as usize + 1).min(8);
+    let bytes = data.get(..len)?;
+    let marker = if len < 8 { 0xff >> len } else { 0 };
+    let value = bytes[1..]
+        .iter()
+        .fold((first & marker) as u64, |acc, &b| acc << 8 | b as u64);
+    Some((len, value))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cluster with a 1 byte size and a 2 byte timecode, followed by `body`.
+    fn cluster(timecode: u16, body: &[u8]) -> Vec<u8> {
+        let mut data = CLUSTER_ID.to_vec();
+        data.push(0x80 | (4 + body.len()) as u8);
+        data.extend_from_slice(&[TIMECODE_ID, 0x82]);
+        data.extend_from_slice(&timecode.to_be_bytes());
+        data.extend_from_slice(body);
+        data
+    }
+
+    /// The timecodes of the clusters `rewrite` wrote, which are always 8 bytes.
+    fn timecodes(data: &[u8]) -> Vec<u64> {
+        let mut timecodes = Vec::new();
+        let mut pos = 0;
+        while let Some(index) = find(&data[pos..], &CLUSTER_ID).map(|i| pos + i) {
+            let ClusterHeader::Valid { timecode, len, .. } = parse_cluster_header(&data[index..])
+            else {
+                panic!("invalid cluster at {}", index);
+            };
+            timecodes.push(timecode);
+            pos = index + len;
+        }
+        timecodes
+    }
+
+    #[test]
+    fn reads_vints_of_every_width() {
+        for len in 1..=8 {
+            let mut data = vec![0; len];
+            data[0] = 0x80 >> (len - 1);
+            data[len - 1] |= 0x05;
+            assert_eq!(read_vint(&data), Some((len, 5)), "width {}", len);
+            assert_eq!(read_vint(&data[..len - 1]), None, "truncated width {}", len);
+        }
+        assert_eq!(read_vint(&[0x42, 0x01]), Some((2, 0x201)));
+        assert_eq!(read_vint(&[0xff]), Some((1, 0x7f)));
+        assert_eq!(read_vint(&[0x00; 8]), Some((8, 0)));
+        assert_eq!(read_vint(&[]), None);
+    }
+
+    #[test]
+    fn parses_cluster_headers() {
+        let data = cluster(0x0102, b"frame");
+        let ClusterHeader::Valid {
+            size,
+            timecode,
+            len,
+            timecode_len,
+        } = parse_cluster_header(&data)
+        else {
+            panic!("not valid");
+        };
+        assert_eq!(size, Some(9));
+        assert_eq!(timecode, 0x0102);
+        assert_eq!(len, 9);
+        assert_eq!(timecode_len, 4);
+
+        for size in [
+            &[0xff][..],
+            &[0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        ] {
+            let mut data = CLUSTER_ID.to_vec();
+            data.extend_from_slice(size);
+            data.extend_from_slice(&[TIMECODE_ID, 0x81, 0x07]);
+            let ClusterHeader::Valid { size, timecode, .. } = parse_cluster_header(&data) else {
+                panic!("not valid");
+            };
+            assert_eq!(size, None);
+            assert_eq!(timecode, 7);
+        }
+
+        for end in CLUSTER_ID.len()..data.len() - 5 {
+            assert!(matches!(
+                parse_cluster_header(&data[..end]),
+                ClusterHeader::Incomplete
+            ));
+        }
+        let mut data = CLUSTER_ID.to_vec();
+        data.extend_from_slice(&[0x84, 0xa3, 0x81, 0x00]);
+        assert!(matches!(
+            parse_cluster_header(&data),
+            ClusterHeader::Invalid
+        ));
+    }
+
+    #[test]
+    fn rewrites_timecodes_to_8_bytes() {
+        let mut timeline = Timeline::default();
+        let data = [b"tail".to_vec(), cluster(300, b"frame")].concat();
+        let out = timeline.rewrite(&data);
+        assert_eq!(&out[..4], b"tail");
+        let ClusterHeader::Valid {
+            size,
+            timecode,
+            len,
+            ..
+        } = parse_cluster_header(&out[4..])
+        else {
+            panic!("not valid");
+        };
+        // the timecode element grew from 4 to 10 bytes
+        assert_eq!(size, Some(15));
+        assert_eq!(timecode, 300);
+        assert_eq!(&out[4 + len..], b"frame");
+    }
+
+    #[test]
+    fn keeps_unknown_sizes() {
+        let mut data = CLUSTER_ID.to_vec();
+        data.extend_from_slice(&[0xff, TIMECODE_ID, 0x81, 0x07]);
+        let out = Timeline::default().rewrite(&data);
+        let ClusterHeader::Valid { size, timecode, .. } = parse_cluster_header(&out) else {
+            panic!("not valid");
+        };
+        assert_eq!(size, None);
+        assert_eq!(timecode, 7);
+    }
+
+    #[test]
+    fn passes_false_matches_through() {
+        let mut data = b"frame".to_vec();
+        data.extend_from_slice(&CLUSTER_ID);
+        data.extend_from_slice(&[0x84, 0xa3, 0x81, 0x00]);
+        assert_eq!(Timeline::default().rewrite(&data), data);
+    }
+
+    #[test]
+    fn holds_back_headers_split_across_chunks() {
+        let data = [cluster(1, b"one"), cluster(2, b"two")].concat();
+        let expected = Timeline::default().rewrite(&data);
+        for split in 1..data.len() {
+            let mut timeline = Timeline::default();
+            let mut out = timeline.rewrite(&data[..split]);
+            out.extend(timeline.rewrite(&data[split..]));
+            assert_eq!(out, expected, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn continues_the_timeline_across_a_splice() {
+        let mut timeline = Timeline::default();
+        let before = timeline.rewrite(&[cluster(1000, b"a"), cluster(2000, b"b")].concat());
+        assert_eq!(timecodes(&before), [1000, 2000]);
+
+        // the new capture starts at 0 again
+        timeline.splice();
+        let after = timeline.rewrite(&[cluster(0, b"c"), cluster(500, b"d")].concat());
+        let after = timecodes(&after);
+        assert!(after[0] >= 2000 && after[0] < 3000, "{:?}", after);
+        assert_eq!(after[1] - after[0], 500);
+    }
+
+    #[test]
+    fn header_ends_at_the_first_cluster() {
+        let mut header = WebmHeader::default();
+        header.observe(&[&EBML_MAGIC[..], b"head"].concat());
+        assert!(!header.complete);
+        header.observe(&[b"er".to_vec(), cluster(0, b"frame")].concat());
+        assert!(header.complete);
+        assert_eq!(header.bytes, [&EBML_MAGIC[..], b"header"].concat());
+
+        header.observe(&cluster(1, b"frame"));
+        assert_eq!(header.bytes, [&EBML_MAGIC[..], b"header"].concat());
+
+        // a restarted capture brings a new header
+        header.observe(&[&EBML_MAGIC[..], b"new"].concat());
+        assert!(!header.complete);
+        assert_eq!(header.bytes, [&EBML_MAGIC[..], b"new"].concat());
+    }
+}
@@ -1,18 +1,55 @@
+mod admin;
 mod browser_capture;
+mod browser_log;
+mod capture;
+mod config;
+mod control;
+mod encoder;
+mod metrics;
+mod page;
+mod plays;
+mod protocol;
+mod scenes;
+mod schedule;
+mod site;
+mod status;
 mod twitch;
 mod ws;
+use admin::run_admin;
 use browser_capture::CapturedBrowser;
-use futures::SinkExt;
+use capture::{Backend, new_backend, run_test_pattern};
+use config::Config;
+use control::Control;
+use encoder::{EncoderControl, run_encoder};
+use page::PageSender;
+use protocol::PageMessage;
+use scenes::MAIN_SCENE;
+use schedule::run_schedule;
+use serde_json::json;
+use site::{ReloadMode, run_site};
+use status::Status;
 use std::{env, time::Duration};
-use tokio::{join, spawn, sync::mpsc, time::sleep};
-use tokio_tungstenite::tungstenite::{Bytes, Message};
+use tokio::{
+    join, select,
+    signal::{
+        ctrl_c,
+        unix::{SignalKind, signal},
+    },
+    spawn,
+    sync::mpsc,
+    task::JoinHandle,
+    time::{Instant, sleep, sleep_until, timeout},
+};
+use tokio_tungstenite::tungstenite::Bytes;
 use tracing::Level;
-use tracing::info;
+use tracing::{info, warn};
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 use twitch::run_twitch;
 use ws::run_ws_stream;
 
 const WS_PORT: u16 = 8080;
+/// How long the capture backend gets to clean up on ctrl-c or SIGTERM.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[tokio::main]
 async fn main() {
@@ -21,62 +58,249 @@ async fn main() {
         .install_default()
         .unwrap();
 
+    let config = Config::load();
     let (stream_tx, stream_rx) = mpsc::channel::<Bytes>(10);
-    let (ws_json_tx, mut ws_json_rx) = mpsc::channel::<String>(10);
+    let (page_tx, page_rx) = mpsc::channel::<PageMessage>(10);
+    let page_sender = PageSender::new(config.backlog.clone());
+    let (control_tx, control_rx) = mpsc::channel::<Control>(10);
+    let (encoder_tx, encoder_rx) = mpsc::channel::<EncoderControl>(10);
+    let status = Status::new();
+    // capture starts on the main scene, scene limited rewards depend on it
+    status.set_scene(MAIN_SCENE);
 
     let twitch_client_id = env::var("TWITCH_CLIENT_ID").unwrap();
     let twitch_client_secret = env::var("TWITCH_CLIENT_SECRET").unwrap();
     let twitch_rtmp_url = env::var("TWITCH_RTMP_URL").unwrap();
 
     info!("running twitch streamer & listener");
-    let (twitch_server_handle, twitch_event_handle) = run_twitch(
+    let (twitch_event_handle, metadata) = run_twitch(
         &twitch_client_id,
         &twitch_client_secret,
-        &twitch_rtmp_url,
-        stream_rx,
-        ws_json_tx,
+        &config,
+        page_sender.clone(),
+        page_rx,
+        control_tx.clone(),
+        status.clone(),
     )
     .await;
+    let twitch_stream_handle = spawn(run_encoder(
+        twitch_rtmp_url,
+        stream_rx,
+        encoder_rx,
+        status.clone(),
+    ));
+
+    // up before the browser loads it
+    let site_handle = run_site(config.site.clone(), control_tx.clone()).await;
 
-    let website = env::var("WEBSITE").unwrap();
     let dimensions = env::var("DIMENSIONS").unwrap();
     let (width, height) = dimensions.split_once('x').unwrap();
-    let width = width.parse().unwrap();
-    let height = height.parse().unwrap();
+    let window_size = (width.parse().unwrap(), height.parse().unwrap());
+    let browser_handle = if config.capture.backend == Backend::TestPattern {
+        info!("streaming a test pattern, dimensions: {}", dimensions);
+        run_test_pattern(
+            config.capture.test_pattern.clone(),
+            window_size,
+            stream_tx.clone(),
+            control_rx,
+            status.clone(),
+        )
+    } else {
+        run_browser(
+            &config,
+            window_size,
+            stream_tx.clone(),
+            control_rx,
+            page_sender.clone(),
+            status.clone(),
+        )
+        .await
+    };
+
+    info!("running ws stream to extension");
+    let ws_handle = run_ws_stream(
+        WS_PORT,
+        stream_tx,
+        page_tx,
+        page_sender.clone(),
+        status.clone(),
+    )
+    .await;
+
+    let schedule_handle = run_schedule(config.schedule.clone(), control_tx.clone());
+
+    let admin_token = env::var("ADMIN_TOKEN").ok();
+    if admin_token.is_none() {
+        info!("ADMIN_TOKEN not set, admin api disabled");
+    }
+    let admin_handle = run_admin(
+        config.admin.clone(),
+        admin_token,
+        control_tx.clone(),
+        encoder_tx,
+        page_sender,
+        metadata,
+        status,
+    )
+    .await;
+
+    let handles = async {
+        join!(
+            ws_handle,
+            twitch_stream_handle,
+            twitch_event_handle,
+            schedule_handle,
+            site_handle,
+            admin_handle
+        )
+    };
+    select! {
+        _ = handles => {}
+        _ = shutdown_signal() => {
+            info!("shutting down");
+            // the browser task takes the capture backend down with it
+            if control_tx.send(Control::Shutdown).await.is_ok()
+                && timeout(SHUTDOWN_TIMEOUT, browser_handle).await.is_err()
+            {
+                warn!("capture did not shut down in time");
+            }
+        }
+    }
+}
+
+/// Resolves on ctrl-c or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    select! {
+        _ = ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+/// Runs the browser and handles `control_rx` for it.
+async fn run_browser(
+    config: &Config,
+    window_size: (u32, u32),
+    stream_tx: mpsc::Sender<Bytes>,
+    mut control_rx: mpsc::Receiver<Control>,
+    page_sender: PageSender,
+    status: Status,
+) -> JoinHandle<()> {
+    let site_url = config.site.url();
+    let website = match (env::var("WEBSITE"), &config.site.dir) {
+        (Ok(website), _) => website,
+        (Err(_), Some(_)) => site_url.clone(),
+        (Err(e), None) => panic!("WEBSITE: {}", e),
+    };
+    let site_reload = config.site.reload;
+    let max_delay = Duration::from_secs(config.site.max_delay);
     let headless = env::var("HEADLESS")
         .unwrap_or("true".to_string())
         .parse()
         .unwrap();
     info!(
-        "running headless browser at site {}, dimensions: {}, headless: {}",
-        website, dimensions, headless
+        "running headless browser at site {}, dimensions: {}x{}, headless: {}",
+        website, window_size.0, window_size.1, headless
     );
-    let mut captured_browser = CapturedBrowser::new((width, height), headless).await;
-    let browser_handle = spawn(async move {
-        sleep(Duration::from_secs(5)).await;
+    let transition = Duration::from_millis(config.capture.transition);
+    let backend = new_backend(&config.capture, WS_PORT, window_size, stream_tx).await;
+    let mut captured_browser = CapturedBrowser::new(
+        config.capture.clone(),
+        backend,
+        config.scenes.clone(),
+        window_size,
+        headless,
+    )
+    .await;
+    spawn(async move {
+        status.update(|s| {
+            s.browser.state = "starting".to_string();
+            s.browser.url = website.clone();
+        });
         info!("starting browser capture");
-        captured_browser.start_capture(&website, WS_PORT).await;
-        loop {
-            sleep(Duration::from_secs(1)).await;
-        }
-    });
-
-    info!("running ws stream to extension");
-    let (ws_handle, mut ws_rx) = run_ws_stream(WS_PORT, stream_tx).await;
-    let ws_forward_handle = spawn(async move {
+        let mut page = captured_browser.start_capture(&website).await;
+        status.update(|s| s.browser.state = "capturing".to_string());
+        // when the site changed under the captured tab, see `ReloadMode::Production`
+        let mut stale_since: Option<Instant> = None;
         loop {
-            let message = ws_json_rx.recv().await.unwrap();
-            ws_rx.send(Message::text(message)).await.unwrap();
+            let deadline = stale_since
+                .filter(|_| !max_delay.is_zero())
+                .map(|since| since + max_delay);
+            let control = select! {
+                control = control_rx.recv() => control,
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    info!("reloading the changed site without waiting longer");
+                    stale_since = None;
+                    captured_browser.reload(&page).await;
+                    continue;
+                }
+                failure = captured_browser.watch(&page) => {
+                    warn!("browser failure: {:?}", failure);
+                    status.update(|s| s.browser.state = "recovering".to_string());
+                    captured_browser.recover(&mut page, failure).await;
+                    status.update(|s| {
+                        s.browser.state = "capturing".to_string();
+                        s.browser.recoveries += 1;
+                    });
+                    continue;
+                }
+            };
+            let Some(control) = control else {
+                break;
+            };
+            info!("control: {:?}", control);
+            match control {
+                Control::Reload => {
+                    stale_since = None;
+                    captured_browser.reload(&page).await;
+                }
+                Control::Navigate(url) => {
+                    stale_since = None;
+                    // the page can fade out, its last frame is repeated while the url loads
+                    let message = json!({ "type": "navigate", "url": url });
+                    page_sender.send(message).await;
+                    sleep(transition).await;
+                    captured_browser.navigate(&page, &url).await;
+                    status.update(|s| s.browser.url = url);
+                }
+                Control::SwitchScene(scene) => {
+                    let previous = page.target_id().clone();
+                    // scenes without a tab of their own are up to the page
+                    if captured_browser.switch_scene(&mut page, &scene).await {
+                        let url = captured_browser.url().to_string();
+                        status.update(|s| s.browser.url = url);
+                    }
+                    // the stale tab is in the background now
+                    if stale_since.is_some() && page.target_id() != &previous {
+                        stale_since = None;
+                        captured_browser.reload_scenes(&site_url).await;
+                    }
+                    status.set_scene(&scene);
+                    let message = json!({ "type": "scene", "scene": scene });
+                    page_sender.send(message).await;
+                }
+                Control::Input(action) => plays::dispatch(&page, action),
+                Control::Screenshot(tx) => {
+                    let _ = tx.send(captured_browser.screenshot(&page).await);
+                }
+                Control::SiteChanged => {
+                    captured_browser.reload_scenes(&site_url).await;
+                    if !captured_browser.url().starts_with(&site_url) {
+                        continue;
+                    }
+                    if site_reload == ReloadMode::Dev || !status.output_started() {
+                        stale_since = None;
+                        captured_browser.reload(&page).await;
+                    } else if stale_since.is_none() {
+                        info!("reloading the changed site at the next scene switch");
+                        stale_since = Some(Instant::now());
+                    }
+                }
+                Control::Shutdown => break,
+            }
         }
-    });
-
-    let _results = join!(
-        browser_handle,
-        ws_handle,
-        ws_forward_handle,
-        twitch_server_handle,
-        twitch_event_handle
-    );
+        captured_browser.shutdown().await;
+    })
 }
 
 fn setup_tracing() {
@@ -0,0 +1,219 @@
+use reqwest::Client;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{LazyLock, Mutex},
+    time::Instant,
+};
+use twitch_api::client::{BoxedFuture, ClientDefault, Request, Response};
+
+/// Every metric with its type and help text, in the order they are rendered.
+const METRICS: &[(&str, &str, &str)] = &[
+    (
+        "webstreamer_extension_bytes_total",
+        "counter",
+        "Bytes of captured media received from the extension.",
+    ),
+    (
+        "webstreamer_extension_frames_total",
+        "counter",
+        "Websocket messages of captured media received from the extension.",
+    ),
+    (
+        "webstreamer_screencast_frames_total",
+        "counter",
+        "Frames received from the DevTools screencast.",
+    ),
+    (
+        "webstreamer_stream_queue_depth",
+        "gauge",
+        "Chunks waiting in the channel from the extension socket to the encoder.",
+    ),
+    (
+        "webstreamer_stream_dropped_chunks_total",
+        "counter",
+        "Captured chunks not sent to ffmpeg, by reason.",
+    ),
+    (
+        "webstreamer_encoder_running",
+        "gauge",
+        "Whether ffmpeg is running.",
+    ),
+    (
+        "webstreamer_encoder_fps",
+        "gauge",
+        "Frames per second ffmpeg reports.",
+    ),
+    (
+        "webstreamer_encoder_bitrate_kbps",
+        "gauge",
+        "Output bitrate ffmpeg reports, in kbit/s.",
+    ),
+    (
+        "webstreamer_encoder_speed",
+        "gauge",
+        "Encoding speed ffmpeg reports, 1 is realtime.",
+    ),
+    (
+        "webstreamer_encoder_restarts_total",
+        "counter",
+        "ffmpeg restarts, by reason.",
+    ),
+    (
+        "webstreamer_eventsub_reconnects_total",
+        "counter",
+        "EventSub websocket reconnections, by session.",
+    ),
+    (
+        "webstreamer_eventsub_messages_total",
+        "counter",
+        "EventSub websocket messages, by message or subscription type.",
+    ),
+    (
+        "webstreamer_helix_request_duration_seconds",
+        "summary",
+        "Helix request latency, by endpoint.",
+    ),
+    (
+        "webstreamer_helix_errors_total",
+        "counter",
+        "Failed Helix requests, by endpoint and status.",
+    ),
+    (
+        "webstreamer_page_dropped_messages_total",
+        "counter",
+        "Messages for the page dropped because its queue was full.",
+    ),
+    (
+        "webstreamer_chat_dropped_messages_total",
+        "counter",
+        "Outgoing chat messages dropped because the send queue was full.",
+    ),
+    (
+        "webstreamer_cache_requests_total",
+        "counter",
+        "Lookups in the chat caches, by cache and hit or miss.",
+    ),
+    (
+        "webstreamer_browser_crashes_total",
+        "counter",
+        "Crashes of the captured page.",
+    ),
+    (
+        "webstreamer_browser_recoveries_total",
+        "counter",
+        "Attempts to recover the captured page, by action.",
+    ),
+];
+
+/// Series by metric name, then by rendered labels.
+static REGISTRY: LazyLock<Mutex<BTreeMap<&'static str, BTreeMap<String, f64>>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+pub fn inc(name: &'static str, labels: &[(&str, &str)]) {
+    add(name, labels, 1.0);
+}
+
+pub fn add(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    let mut registry = REGISTRY.lock().unwrap();
+    *registry
+        .entry(name)
+        .or_default()
+        .entry(render_labels(labels))
+        .or_default() += value;
+}
+
+pub fn set(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry
+        .entry(name)
+        .or_default()
+        .insert(render_labels(labels), value);
+}
+
+/// Records one observation of a summary, as its `_sum` and `_count`.
+fn observe(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    let labels = render_labels(labels);
+    let mut registry = REGISTRY.lock().unwrap();
+    let series = registry.entry(name).or_default();
+    *series.entry(format!("_sum{}", labels)).or_default() += value;
+    *series.entry(format!("_count{}", labels)).or_default() += 1.0;
+}
+
+/// All metrics in the Prometheus text format.
+pub fn render() -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let mut out = String::new();
+    for (name, kind, help) in METRICS {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+        for (labels, value) in registry.get(name).into_iter().flatten() {
+            writeln!(out, "{}{} {}", name, labels, value).unwrap();
+        }
+    }
+    out
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// The reqwest client Helix requests go through, timing them by endpoint.
+#[derive(Clone)]
+pub struct MeteredClient(pub Client);
+
+impl twitch_api::HttpClient for MeteredClient {
+    type Error = <Client as twitch_api::HttpClient>::Error;
+
+    fn req(&self, request: Request) -> BoxedFuture<'_, Result<Response, Self::Error>> {
+        let endpoint = request
+            .uri()
+            .path()
+            .trim_start_matches("/helix")
+            .to_string();
+        let started = Instant::now();
+        let response = self.0.req(request);
+        Box::pin(async move {
+            let response = response.await;
+            let labels = [("endpoint", endpoint.as_str())];
+            observe(
+                "webstreamer_helix_request_duration_seconds",
+                &labels,
+                started.elapsed().as_secs_f64(),
+            );
+            let status = match &response {
+                Ok(response) if response.status().is_success() => None,
+                Ok(response) => Some(response.status().as_u16().to_string()),
+                Err(_) => Some("error".to_string()),
+            };
+            if let Some(status) = status {
+                let labels = [("endpoint", endpoint.as_str()), ("status", status.as_str())];
+                inc("webstreamer_helix_errors_total", &labels);
+            }
+            response
+        })
+    }
+}
+
+impl ClientDefault<'static> for MeteredClient {
+    type Error = <Client as ClientDefault<'static>>::Error;
+
+    fn default_client_with_name(
+        product: Option<reqwest::header::HeaderValue>,
+    ) -> Result<Self, Self::Error> {
+        Client::default_client_with_name(product).map(MeteredClient)
+    }
+}
@@ -0,0 +1,408 @@
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
+use tokio::sync::{
+    Mutex,
+    mpsc::{self, error::TrySendError},
+};
+use tracing::{info, warn};
+
+use crate::metrics;
+
+/// Messages queued for the page before new ones are dropped.
+const QUEUE_SIZE: usize = 100;
+
+/// How many recent messages to keep per message type, or per subscription
+/// type for `twitch-event` messages. Configured limits are merged over the
+/// defaults, 0 turns one off.
+#[derive(Debug, Clone)]
+pub struct BacklogConfig(pub HashMap<String, usize>);
+
+impl Default for BacklogConfig {
+    fn default() -> Self {
+        BacklogConfig(HashMap::from([
+            ("twitch-chat".to_string(), 50),
+            ("channel.chat.notification".to_string(), 20),
+            ("channel.raid".to_string(), 10),
+            ("alert".to_string(), 10),
+            ("poll-end".to_string(), 5),
+        ]))
+    }
+}
+
+impl<'de> Deserialize<'de> for BacklogConfig {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut limits = BacklogConfig::default().0;
+        limits.extend(HashMap::<String, usize>::deserialize(deserializer)?);
+        Ok(BacklogConfig(limits))
+    }
+}
+
+/// Sends messages to the page. Each message gets a `msg_id` that increases for
+/// the server's lifetime, which backlogs and history refer to, and a `seq`
+/// that increases by one per message delivered on the current connection, so
+/// the page can detect gaps even when its subscription filters messages out.
+/// Recent messages are kept per type so a page that (re)connects can catch up,
+/// and messages the page didn't subscribe to are dropped before they are
+/// serialized.
+#[derive(Clone)]
+pub struct PageSender {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    id: u64,
+    connection: Option<Connection>,
+    /// How many messages to keep per type.
+    limits: HashMap<String, usize>,
+    backlog: HashMap<String, VecDeque<Value>>,
+}
+
+struct Connection {
+    tx: mpsc::Sender<String>,
+    /// Messages delivered on this connection, backlogs don't count.
+    seq: u64,
+    filter: PageFilter,
+    /// Whether the page asked for messages yet, nothing is sent before so its
+    /// subscription applies to the first backlog.
+    started: bool,
+}
+
+impl PageSender {
+    pub fn new(backlog: BacklogConfig) -> Self {
+        PageSender {
+            inner: Arc::new(Mutex::new(Inner {
+                id: 0,
+                connection: None,
+                limits: backlog.0,
+                backlog: HashMap::new(),
+            })),
+        }
+    }
+
+    pub async fn send(&self, mut message: Value) {
+        // held until the message is queued so ids and sequence numbers stay in order
+        let mut inner = self.inner.lock().await;
+        inner.id += 1;
+        message["msg_id"] = json!(inner.id);
+
+        let key = backlog_key(&message);
+        let limit = inner.limits.get(&key).copied().unwrap_or(0);
+        if limit > 0 {
+            let backlog = inner.backlog.entry(key).or_default();
+            backlog.push_back(message.clone());
+            if backlog.len() > limit {
+                backlog.pop_front();
+            }
+        }
+
+        let Some(connection) = inner.connection.as_mut() else {
+            return;
+        };
+        if !connection.started || !connection.filter.allows(&message) {
+            return;
+        }
+        connection.seq += 1;
+        message["seq"] = json!(connection.seq);
+        if !connection.queue(message.to_string()) {
+            inner.connection = None;
+        }
+    }
+
+    /// Replaces any previous connection and its subscription. Messages are
+    /// queued once the page subscribes or asks for history, or on [`start`].
+    ///
+    /// [`start`]: PageSender::start
+    pub async fn connect(&self) -> mpsc::Receiver<String> {
+        let mut inner = self.inner.lock().await;
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        inner.connection = Some(Connection {
+            tx,
+            seq: 0,
+            filter: PageFilter::default(),
+            started: false,
+        });
+        rx
+    }
+
+    /// Starts sending everything, beginning with the whole backlog, to a page
+    /// that didn't ask for anything.
+    pub async fn start(&self) {
+        let mut inner = self.inner.lock().await;
+        if inner.connection.as_ref().is_some_and(|c| !c.started) {
+            inner.queue_history(0);
+        }
+    }
+
+    /// Queues a backlog of the messages with a `msg_id` after `since`.
+    pub async fn history(&self, since: u64) {
+        self.inner.lock().await.queue_history(since);
+    }
+
+    /// Restricts live messages and history to what the page asked for, see
+    /// [`PageFilter`]. The first subscription starts the connection with a
+    /// backlog of the messages after `since`.
+    pub async fn subscribe(&self, filter: PageFilter, since: u64) {
+        info!("page subscribed: {:?}", filter);
+        let mut inner = self.inner.lock().await;
+        let Some(connection) = inner.connection.as_mut() else {
+            return;
+        };
+        connection.filter = filter;
+        if !connection.started {
+            inner.queue_history(since);
+        }
+    }
+}
+
+impl Inner {
+    /// Also starts the connection, live messages follow the backlog.
+    fn queue_history(&mut self, since: u64) {
+        let Some(connection) = self.connection.as_mut() else {
+            return;
+        };
+        connection.started = true;
+        let mut messages: Vec<&Value> = self
+            .backlog
+            .values()
+            .flatten()
+            .filter(|m| m["msg_id"].as_u64().unwrap_or(0) > since && connection.filter.allows(m))
+            .collect();
+        messages.sort_by_key(|m| m["msg_id"].as_u64());
+        let backlog = json!({
+            "type": "backlog",
+            "since": since,
+            "msg_id": self.id,
+            "seq": connection.seq,
+            "messages": messages,
+        });
+        if !connection.queue(backlog.to_string()) {
+            self.connection = None;
+        }
+    }
+}
+
+impl Connection {
+    /// Queues without waiting, so a slow page can't hold up the server. A
+    /// full queue drops the message, which the page sees as a gap in `seq`.
+    /// Returns false once the connection is gone.
+    fn queue(&self, message: String) -> bool {
+        match self.tx.try_send(message) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("page queue full, dropping message");
+                metrics::inc("webstreamer_page_dropped_messages_total", &[]);
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+/// What the page wants to receive, from a `subscribe` message like
+/// `{ "type": "subscribe", "categories": ["chat"], "channels": ["login"], "rewards": ["id"] }`.
+/// Fields that are left out don't filter anything.
+#[derive(Debug, Default)]
+pub struct PageFilter {
+    pub categories: Option<HashSet<String>>,
+    /// Channel ids or logins.
+    pub channels: Option<HashSet<String>>,
+    /// Channel point reward ids, only applies to redemptions.
+    pub rewards: Option<HashSet<String>>,
+}
+
+impl PageFilter {
+    fn allows(&self, message: &Value) -> bool {
+        if let (Some(categories), Some(category)) = (&self.categories, category(message))
+            && !categories.contains(category)
+        {
+            return false;
+        }
+        if let Some(channels) = &self.channels
+            && message["channel"].is_object()
+        {
+            let channel = &message["channel"];
+            let matches = |key: &str| {
+                channel[key]
+                    .as_str()
+                    .is_some_and(|value| channels.contains(value))
+            };
+            if !matches("id") && !matches("login") {
+                return false;
+            }
+        }
+        if let (Some(rewards), Some(reward_id)) = (&self.rewards, message["reward_id"].as_str())
+            && !rewards.contains(reward_id)
+        {
+            return false;
+        }
+        true
+    }
+}
+
+/// Messages without a category (backlogs, scene changes) always go through.
+fn category(message: &Value) -> Option<&'static str> {
+    match message["type"].as_str()? {
+        "twitch-chat" => Some("chat"),
+        "chat-command" => Some("commands"),
+        "alert" | "alert-update" | "alert-cancel" | "redemption-timeout" => Some("alerts"),
+        "poll-start" | "poll-tally" | "poll-end" => Some("polls"),
+        "plays-input" | "plays-mode" => Some("plays"),
+        "twitch-event" => Some(match message["event_type"].as_str()? {
+            "channel.chat.notification"
+            | "channel.follow"
+            | "channel.subscribe"
+            | "channel.subscription.gift"
+            | "channel.subscription.message"
+            | "channel.cheer"
+            | "channel.raid"
+            | "channel.channel_points_custom_reward_redemption.add" => "alerts",
+            _ => "events",
+        }),
+        _ => None,
+    }
+}
+
+/// Twitch events are kept by their subscription type, everything else by message type.
+fn backlog_key(message: &Value) -> String {
+    message["event_type"]
+        .as_str()
+        .or(message["type"].as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn categories(categories: &[&str]) -> PageFilter {
+        PageFilter {
+            categories: Some(categories.iter().map(|c| c.to_string()).collect()),
+            ..PageFilter::default()
+        }
+    }
+
+    fn received(rx: &mut mpsc::Receiver<String>) -> Vec<Value> {
+        let mut messages = Vec::new();
+        while let Ok(message) = rx.try_recv() {
+            messages.push(serde_json::from_str(&message).unwrap());
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn filtered_messages_leave_no_gaps() {
+        let page = PageSender::new(BacklogConfig::default());
+        let mut rx = page.connect().await;
+        page.subscribe(categories(&["chat"]), 0).await;
+        received(&mut rx);
+
+        page.send(json!({ "type": "twitch-chat", "text": "a" }))
+            .await;
+        page.send(json!({ "type": "alert", "kind": "follow" }))
+            .await;
+        page.send(json!({ "type": "twitch-chat", "text": "b" }))
+            .await;
+
+        let messages = received(&mut rx);
+        let seqs: Vec<_> = messages.iter().map(|m| m["seq"].as_u64()).collect();
+        let ids: Vec<_> = messages.iter().map(|m| m["msg_id"].as_u64()).collect();
+        assert_eq!(seqs, [Some(1), Some(2)]);
+        assert_eq!(ids, [Some(1), Some(3)]);
+    }
+
+    #[tokio::test]
+    async fn history_is_filtered_by_subscription_and_id() {
+        let page = PageSender::new(BacklogConfig::default());
+        page.send(json!({ "type": "twitch-chat", "text": "a" }))
+            .await;
+        page.send(json!({ "type": "alert", "kind": "follow" }))
+            .await;
+        page.send(json!({ "type": "twitch-chat", "text": "b" }))
+            .await;
+
+        let mut rx = page.connect().await;
+        page.subscribe(categories(&["chat"]), 1).await;
+
+        let backlog = received(&mut rx).remove(0);
+        assert_eq!(backlog["type"], "backlog");
+        assert_eq!(backlog["msg_id"], 3);
+        assert_eq!(backlog["seq"], 0);
+        let messages = backlog["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["text"], "b");
+        assert_eq!(messages[0]["msg_id"], 3);
+        assert!(messages[0].get("seq").is_none());
+    }
+
+    #[tokio::test]
+    async fn reconnecting_restarts_seq() {
+        let page = PageSender::new(BacklogConfig::default());
+        let mut rx = page.connect().await;
+        page.start().await;
+        page.send(json!({ "type": "twitch-chat", "text": "a" }))
+            .await;
+        let mut rx2 = page.connect().await;
+        page.start().await;
+        page.send(json!({ "type": "twitch-chat", "text": "b" }))
+            .await;
+
+        assert_eq!(received(&mut rx).last().unwrap()["seq"], 1);
+        let messages = received(&mut rx2);
+        assert_eq!(messages[0]["messages"][0]["text"], "a");
+        assert_eq!(messages[1]["seq"], 1);
+        assert_eq!(messages[1]["msg_id"], 2);
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_page_before_sending() {
+        let page = PageSender::new(BacklogConfig::default());
+        let mut rx = page.connect().await;
+        page.send(json!({ "type": "twitch-chat", "text": "a" }))
+            .await;
+        page.send(json!({ "type": "alert", "kind": "follow" }))
+            .await;
+        assert!(received(&mut rx).is_empty());
+
+        page.subscribe(categories(&["alerts"]), 0).await;
+        page.subscribe(categories(&["alerts"]), 0).await;
+        let messages = received(&mut rx);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["messages"][0]["kind"], "follow");
+
+        // already started
+        page.start().await;
+        assert!(received(&mut rx).is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_payload_id() {
+        let page = PageSender::new(BacklogConfig::default());
+        let mut rx = page.connect().await;
+        page.start().await;
+        page.send(json!({ "type": "alert", "id": 7, "kind": "follow" }))
+            .await;
+        page.send(json!({ "type": "redemption-timeout", "id": "abc" }))
+            .await;
+
+        let messages = received(&mut rx);
+        assert_eq!(messages[1]["id"], 7);
+        assert_eq!(messages[1]["msg_id"], 1);
+        assert_eq!(messages[2]["id"], "abc");
+        assert_eq!(messages[2]["msg_id"], 2);
+    }
+
+    #[test]
+    fn backlog_config_merges_over_defaults() {
+        let config: BacklogConfig =
+            serde_json::from_value(json!({ "twitch-chat": 100, "alert": 0, "scene": 1 })).unwrap();
+        assert_eq!(config.0["twitch-chat"], 100);
+        assert_eq!(config.0["alert"], 0);
+        assert_eq!(config.0["scene"], 1);
+        assert_eq!(config.0["channel.raid"], 10);
+    }
+}
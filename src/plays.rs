@@ -0,0 +1,369 @@
+use chromiumoxide::{
+    Page,
+    cdp::browser_protocol::input::{
+        DispatchKeyEventParams, DispatchKeyEventType, DispatchMouseEventParams,
+        DispatchMouseEventType, MouseButton,
+    },
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::{
+    select, spawn,
+    sync::mpsc::{self, Sender, error::TrySendError},
+    task::JoinHandle,
+    time::{interval, sleep},
+};
+use tracing::{debug, info, warn};
+
+use crate::{control::Control, page::PageSender};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PlaysConfig {
+    pub enabled: bool,
+    pub mode: PlaysMode,
+    /// Milliseconds per democracy vote.
+    pub window: u64,
+    /// Milliseconds between two inputs from the same chatter.
+    pub user_cooldown: u64,
+    /// Chat message (case-insensitive) to the input it triggers. Nothing else
+    /// is ever sent to the tab.
+    pub inputs: HashMap<String, InputAction>,
+}
+
+impl Default for PlaysConfig {
+    fn default() -> Self {
+        PlaysConfig {
+            enabled: false,
+            mode: PlaysMode::Anarchy,
+            window: 5000,
+            user_cooldown: 1000,
+            inputs: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlaysMode {
+    /// Every input is executed as it comes in.
+    #[default]
+    Anarchy,
+    /// Only the most sent input of each window is executed.
+    Democracy,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InputAction {
+    /// A key by its DOM `key` value, e.g. `ArrowUp`, `a` or ` `.
+    Key {
+        key: String,
+        /// DOM `code`, defaults to one derived from `key`.
+        #[serde(default)]
+        code: Option<String>,
+        /// Milliseconds to hold the key down.
+        #[serde(default = "default_hold")]
+        hold: u64,
+    },
+    /// A click at page coordinates.
+    Click {
+        x: f64,
+        y: f64,
+        #[serde(default)]
+        button: Button,
+        #[serde(default = "default_hold")]
+        hold: u64,
+    },
+}
+
+fn default_hold() -> u64 {
+    100
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Button {
+    #[default]
+    Left,
+    Middle,
+    Right,
+}
+
+enum Input {
+    Chat { user: String, text: String },
+    Mode(PlaysMode),
+}
+
+/// Turns chat messages into browser input, see [`PlaysConfig`]. Inputs are
+/// executed by the browser task through [`Control::Input`].
+#[derive(Clone)]
+pub struct Plays {
+    tx: mpsc::Sender<Input>,
+}
+
+impl Plays {
+    pub fn spawn(
+        config: PlaysConfig,
+        page: PageSender,
+        control_tx: Sender<Control>,
+    ) -> (Self, JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(100);
+        let handle = spawn(run(config, page, control_tx, rx));
+        (Plays { tx }, handle)
+    }
+
+    /// Doesn't wait, so a busy browser can't hold up chat. Messages that
+    /// don't fit in the queue are dropped.
+    pub fn chat(&self, user: &str, text: &str) {
+        let input = Input::Chat {
+            user: user.to_string(),
+            text: text.to_string(),
+        };
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(input) {
+            debug!("plays queue full, dropping chat input from {}", user);
+        }
+    }
+
+    pub async fn set_mode(&self, mode: PlaysMode) {
+        self.tx.send(Input::Mode(mode)).await.unwrap();
+    }
+}
+
+async fn run(
+    config: PlaysConfig,
+    page: PageSender,
+    control_tx: Sender<Control>,
+    mut rx: mpsc::Receiver<Input>,
+) {
+    let inputs: HashMap<String, InputAction> = config
+        .inputs
+        .into_iter()
+        .map(|(name, action)| (name.to_lowercase(), action))
+        .collect();
+    let mut mode = config.mode;
+    let cooldown = Duration::from_millis(config.user_cooldown);
+    let mut last_input = HashMap::<String, Instant>::new();
+    let mut votes = HashMap::<String, Vec<String>>::new();
+    let mut windows = interval(Duration::from_millis(config.window.max(100)));
+
+    loop {
+        select! {
+            input = rx.recv() => match input {
+                None => break,
+                Some(Input::Mode(new_mode)) => {
+                    info!("plays mode: {:?}", new_mode);
+                    mode = new_mode;
+                    votes.clear();
+                    windows.reset();
+                    let name = format!("{:?}", mode).to_lowercase();
+                    page.send(json!({ "type": "plays-mode", "mode": name })).await;
+                }
+                Some(Input::Chat { user, text }) => {
+                    let name = text.trim().to_lowercase();
+                    let Some(action) = inputs.get(&name) else {
+                        continue;
+                    };
+                    if last_input.get(&user).is_some_and(|t| t.elapsed() < cooldown) {
+                        debug!("plays input from {} on cooldown", user);
+                        continue;
+                    }
+                    last_input.retain(|_, t| t.elapsed() < cooldown);
+                    last_input.insert(user.clone(), Instant::now());
+                    match mode {
+                        PlaysMode::Anarchy => {
+                            execute(&page, &control_tx, &name, action, vec![user]).await
+                        }
+                        PlaysMode::Democracy => votes.entry(name).or_default().push(user),
+                    }
+                }
+            },
+            _ = windows.tick(), if mode == PlaysMode::Democracy => {
+                if let Some((name, users)) = democracy_winner(&mut votes) {
+                    execute(&page, &control_tx, &name, &inputs[&name], users).await;
+                }
+            }
+        }
+    }
+}
+
+/// The input with the most votes in the window, ties go to the name that
+/// sorts first. Clears the votes for the next window.
+fn democracy_winner(votes: &mut HashMap<String, Vec<String>>) -> Option<(String, Vec<String>)> {
+    votes
+        .drain()
+        .max_by_key(|(name, users)| (users.len(), std::cmp::Reverse(name.clone())))
+}
+
+async fn execute(
+    page: &PageSender,
+    control_tx: &Sender<Control>,
+    name: &str,
+    action: &InputAction,
+    users: Vec<String>,
+) {
+    debug!("plays input {} from {:?}", name, users);
+    // inputs are only worth anything right away
+    match control_tx.try_send(Control::Input(action.clone())) {
+        Ok(()) => {
+            page.send(json!({ "type": "plays-input", "input": name, "users": users }))
+                .await
+        }
+        Err(TrySendError::Full(_)) => warn!("controls busy, dropping plays input {}", name),
+        Err(TrySendError::Closed(_)) => warn!("controls closed, dropping plays input {}", name),
+    }
+}
+
+/// Dispatches the input to the tab through CDP, in the background so holds
+/// don't block other controls.
+pub fn dispatch(page: &Page, action: InputAction) {
+    let page = page.clone();
+    spawn(async move {
+        let result = match &action {
+            InputAction::Key { key, code, hold } => {
+                let code = code.clone().unwrap_or_else(|| key_code(key));
+                let event = |kind| {
+                    let down = matches!(kind, DispatchKeyEventType::KeyDown);
+                    let mut event = DispatchKeyEventParams::new(kind);
+                    event.key = Some(key.clone());
+                    event.code = Some(code.clone());
+                    event.windows_virtual_key_code = virtual_key_code(key);
+                    // printable keys also need text to produce input
+                    if down && key.chars().count() == 1 {
+                        event.text = Some(key.clone());
+                    }
+                    event
+                };
+                async {
+                    page.execute(event(DispatchKeyEventType::KeyDown)).await?;
+                    sleep(Duration::from_millis(*hold)).await;
+                    page.execute(event(DispatchKeyEventType::KeyUp)).await
+                }
+                .await
+                .map(|_| ())
+            }
+            InputAction::Click { x, y, button, hold } => {
+                let button = match button {
+                    Button::Left => MouseButton::Left,
+                    Button::Middle => MouseButton::Middle,
+                    Button::Right => MouseButton::Right,
+                };
+                let event = |kind| {
+                    let mut event = DispatchMouseEventParams::new(kind, *x, *y);
+                    event.button = Some(button.clone());
+                    event.click_count = Some(1);
+                    event
+                };
+                async {
+                    page.execute(event(DispatchMouseEventType::MouseMoved))
+                        .await?;
+                    page.execute(event(DispatchMouseEventType::MousePressed))
+                        .await?;
+                    sleep(Duration::from_millis(*hold)).await;
+                    page.execute(event(DispatchMouseEventType::MouseReleased))
+                        .await
+                }
+                .await
+                .map(|_| ())
+            }
+        };
+        if let Err(e) = result {
+            warn!("failed to dispatch input {:?}: {}", action, e);
+        }
+    });
+}
+
+/// The DOM `code` for keys where it can be derived, e.g. `a` -> `KeyA`.
+fn key_code(key: &str) -> String {
+    let mut chars = key.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_alphabetic() => format!("Key{}", c.to_ascii_uppercase()),
+        (Some(c), None) if c.is_ascii_digit() => format!("Digit{}", c),
+        (Some(' '), None) => "Space".to_string(),
+        _ => key.to_string(),
+    }
+}
+
+/// Windows key codes, which pages reading the deprecated `keyCode` rely on.
+fn virtual_key_code(key: &str) -> Option<i64> {
+    let mut chars = key.chars();
+    if let (Some(c), None) = (chars.next(), chars.next())
+        && c.is_ascii_alphanumeric()
+    {
+        return Some(c.to_ascii_uppercase() as i64);
+    }
+    let code = match key {
+        " " => 32,
+        "Backspace" => 8,
+        "Tab" => 9,
+        "Enter" => 13,
+        "Shift" => 16,
+        "Control" => 17,
+        "Alt" => 18,
+        "Escape" => 27,
+        "ArrowLeft" => 37,
+        "ArrowUp" => 38,
+        "ArrowRight" => 39,
+        "ArrowDown" => 40,
+        _ => return None,
+    };
+    Some(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn votes(entries: &[(&str, &[&str])]) -> HashMap<String, Vec<String>> {
+        entries
+            .iter()
+            .map(|(name, users)| {
+                let users = users.iter().map(|u| u.to_string()).collect();
+                (name.to_string(), users)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn key_codes() {
+        assert_eq!(key_code("a"), "KeyA");
+        assert_eq!(key_code("Z"), "KeyZ");
+        assert_eq!(key_code("7"), "Digit7");
+        assert_eq!(key_code(" "), "Space");
+        assert_eq!(key_code("ArrowUp"), "ArrowUp");
+        assert_eq!(key_code("-"), "-");
+    }
+
+    #[test]
+    fn virtual_key_codes() {
+        assert_eq!(virtual_key_code("a"), Some(65));
+        assert_eq!(virtual_key_code("A"), Some(65));
+        assert_eq!(virtual_key_code("0"), Some(48));
+        assert_eq!(virtual_key_code(" "), Some(32));
+        assert_eq!(virtual_key_code("Enter"), Some(13));
+        assert_eq!(virtual_key_code("ArrowDown"), Some(40));
+        assert_eq!(virtual_key_code("-"), None);
+        assert_eq!(virtual_key_code("F13"), None);
+    }
+
+    #[test]
+    fn democracy_picks_the_most_votes() {
+        let mut window = votes(&[("up", &["a"]), ("left", &["b", "c"]), ("a", &["d"])]);
+        let (name, users) = democracy_winner(&mut window).unwrap();
+        assert_eq!(name, "left");
+        assert_eq!(users, ["b", "c"]);
+        assert!(window.is_empty());
+        assert!(democracy_winner(&mut window).is_none());
+    }
+
+    #[test]
+    fn democracy_ties_go_to_the_first_name() {
+        let mut window = votes(&[("up", &["a", "b"]), ("down", &["c", "d"]), ("a", &["e"])]);
+        let (name, _) = democracy_winner(&mut window).unwrap();
+        assert_eq!(name, "down");
+    }
+}
@@ -0,0 +1,172 @@
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashSet;
+
+use crate::{plays::PlaysMode, twitch::Weight};
+
+/// Bumped whenever a message changes incompatibly.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Fields of a message the page can send, for the sdk.
+pub struct Method {
+    pub name: &'static str,
+    pub required: &'static [&'static str],
+    pub optional: &'static [&'static str],
+}
+
+/// Defines [`PageMessage`] and [`METHODS`] from one list, so the sdk knows
+/// exactly the messages the server parses. Fields marked `?` are optional.
+macro_rules! page_messages {
+    ($(
+        $(#[doc = $doc:literal])*
+        $variant:ident($name:literal) {
+            $($required:ident: $required_type:ty,)*
+            $(?$optional:ident: $optional_type:ty,)*
+        }
+    )*) => {
+        /// A message from the page. `history` and `subscribe` are handled in
+        /// `ws.rs`, everything else in `twitch.rs`.
+        #[derive(Debug, Deserialize)]
+        #[serde(tag = "type")]
+        pub enum PageMessage {
+            $(
+                $(#[doc = $doc])*
+                #[serde(rename = $name)]
+                $variant {
+                    $($required: $required_type,)*
+                    $(#[serde(default)] $optional: $optional_type,)*
+                },
+            )*
+        }
+
+        /// Everything the page can send. The injected sdk refuses anything else.
+        pub const METHODS: &[Method] = &[
+            $(Method {
+                name: $name,
+                required: &[$(stringify!($required)),*],
+                optional: &[$(stringify!($optional)),*],
+            },)*
+        ];
+    };
+}
+
+page_messages! {
+    /// A backlog of the messages after `since`.
+    History("history") {
+        since: u64,
+    }
+    /// Restricts what the page receives, fields that are left out don't filter.
+    Subscribe("subscribe") {
+        ?categories: Option<HashSet<String>>,
+        ?channels: Option<HashSet<String>>,
+        ?rewards: Option<HashSet<String>>,
+        ?since: Option<u64>,
+    }
+    ChatSend("chat-send") {
+        text: String,
+        ?reply_to: Option<String>,
+        ?broadcaster_id: Option<String>,
+    }
+    AlertDone("alert-done") {
+        id: u64,
+    }
+    AlertPause("alert-pause") {}
+    AlertResume("alert-resume") {}
+    AlertSkip("alert-skip") {}
+    /// The last alert without an `id`.
+    AlertReplay("alert-replay") {
+        ?id: Option<u64>,
+    }
+    PollStart("poll-start") {
+        title: String,
+        options: Vec<String>,
+        duration: u64,
+        ?changeable: bool,
+        ?weight: Weight,
+        ?twitch: bool,
+    }
+    PollEnd("poll-end") {}
+    Marker("marker") {
+        ?description: Option<String>,
+    }
+    Clip("clip") {}
+    RedemptionFulfill("redemption-fulfill") {
+        id: String,
+    }
+    RedemptionCancel("redemption-cancel") {
+        id: String,
+    }
+    PlaysMode("plays-mode") {
+        mode: PlaysMode,
+    }
+    SwitchScene("switch-scene") {
+        scene: String,
+    }
+}
+
+/// Every message type sent to the page.
+pub const EVENTS: &[&str] = &[
+    "backlog",
+    "twitch-chat",
+    "twitch-event",
+    "chat-command",
+    "alert",
+    "alert-update",
+    "alert-cancel",
+    "redemption-timeout",
+    "poll-start",
+    "poll-tally",
+    "poll-end",
+    "plays-input",
+    "plays-mode",
+    "stream-marker",
+    "clip",
+    "navigate",
+    "scene",
+];
+
+/// `sdk.js` with the protocol above filled in, injected into every tab.
+pub fn sdk_script() -> String {
+    let methods: serde_json::Map<String, serde_json::Value> = METHODS
+        .iter()
+        .map(|method| {
+            let fields = json!({ "required": method.required, "optional": method.optional });
+            (method.name.to_string(), fields)
+        })
+        .collect();
+    let protocol = json!({
+        "version": PROTOCOL_VERSION,
+        "server": env!("CARGO_PKG_VERSION"),
+        "methods": methods,
+        "events": EVENTS,
+    });
+    include_str!("sdk.js").replace("__WEBSTREAMER_PROTOCOL__", &protocol.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn methods_list_the_message_fields() {
+        let poll_start = METHODS.iter().find(|m| m.name == "poll-start").unwrap();
+        assert_eq!(poll_start.required, ["title", "options", "duration"]);
+        assert_eq!(poll_start.optional, ["changeable", "weight", "twitch"]);
+        let clip = METHODS.iter().find(|m| m.name == "clip").unwrap();
+        assert!(clip.required.is_empty() && clip.optional.is_empty());
+    }
+
+    #[test]
+    fn parses_by_type() {
+        let message = json!({ "type": "chat-send", "text": "hi" });
+        let message: PageMessage = serde_json::from_value(message).unwrap();
+        assert!(matches!(
+            message,
+            PageMessage::ChatSend { text, reply_to: None, broadcaster_id: None } if text == "hi"
+        ));
+        let missing = json!({ "type": "alert-done" });
+        assert!(serde_json::from_value::<PageMessage>(missing).is_err());
+        let unknown = json!({ "type": "nope" });
+        assert!(serde_json::from_value::<PageMessage>(unknown).is_err());
+    }
+}
@@ -0,0 +1,48 @@
+use serde::Deserialize;
+
+/// The scene of the `WEBSITE` tab capture starts on.
+pub const MAIN_SCENE: &str = "main";
+
+/// Scenes shown in their own tabs, preloaded in the background so switching
+/// to one is instant. Scene names that aren't listed are only sent to the
+/// page as a `scene` message.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ScenesConfig {
+    pub list: Vec<SceneConfig>,
+    /// Used by scenes without their own transition.
+    pub transition: Transition,
+    /// Scene shown during ad breaks on your channel, switched back from when
+    /// the break ends.
+    pub ad_break: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SceneConfig {
+    pub name: String,
+    pub url: String,
+    /// Transition into this scene.
+    #[serde(default)]
+    pub transition: Option<Transition>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transition {
+    #[default]
+    Cut,
+    /// Fade through black, taking this many milliseconds each way.
+    Fade(u64),
+}
+
+impl ScenesConfig {
+    pub fn get(&self, name: &str) -> Option<&SceneConfig> {
+        self.list.iter().find(|scene| scene.name == name)
+    }
+
+    pub fn transition(&self, name: &str) -> Transition {
+        self.get(name)
+            .and_then(|scene| scene.transition)
+            .unwrap_or(self.transition)
+    }
+}
@@ -0,0 +1,71 @@
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::{spawn, sync::mpsc::Sender, task::JoinHandle, time::sleep};
+use tracing::{info, warn};
+
+use crate::{control::Control, status::unix_now};
+
+const DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ScheduleConfig {
+    pub list: Vec<ScheduledUrl>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScheduledUrl {
+    /// Time of day as `HH:MM`, in UTC.
+    pub at: String,
+    pub url: String,
+}
+
+/// Navigates to each scheduled url every day at its time.
+pub fn run_schedule(config: ScheduleConfig, control_tx: Sender<Control>) -> JoinHandle<()> {
+    let mut entries: Vec<(u64, String)> = config
+        .list
+        .into_iter()
+        .filter_map(|entry| match parse_time(&entry.at) {
+            Some(at) => Some((at, entry.url)),
+            None => {
+                warn!("invalid schedule time {:?}, expected HH:MM", entry.at);
+                None
+            }
+        })
+        .collect();
+    entries.sort();
+    spawn(async move {
+        if entries.is_empty() {
+            return;
+        }
+        loop {
+            let now = unix_now() % DAY;
+            // the next entry today, or the first one tomorrow
+            let (at, url) = entries
+                .iter()
+                .find(|(at, _)| *at > now)
+                .unwrap_or(&entries[0]);
+            let wait = match (at + DAY - now) % DAY {
+                0 => DAY,
+                wait => wait,
+            };
+            sleep(Duration::from_secs(wait)).await;
+            info!("scheduled navigation to {}", url);
+            if control_tx
+                .send(Control::Navigate(url.clone()))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    })
+}
+
+/// Seconds into the day of an `HH:MM` time.
+fn parse_time(time: &str) -> Option<u64> {
+    let (hours, minutes) = time.split_once(':')?;
+    let hours: u64 = hours.parse().ok()?;
+    let minutes: u64 = minutes.parse().ok()?;
+    (hours < 24 && minutes < 60).then_some(hours * 3600 + minutes * 60)
+}
@@ -0,0 +1,155 @@
+// Injected into every tab before the page's own scripts, see `protocol.rs`
+// which fills in the protocol. Wraps the extension's `postMessage` plumbing:
+//
+//   webstreamer.on("twitch-chat", (message) => ...);
+//   webstreamer.call("chat-send", { text: "hi" });
+//   webstreamer.ready();
+//
+// Messages are delivered once and in order across reconnects: the backlog a
+// new connection starts with is replayed without what was already seen, and
+// gaps are filled from the server's history. Calls made while disconnected
+// are sent once the connection is back.
+(() => {
+  const protocol = __WEBSTREAMER_PROTOCOL__;
+  const MAX_QUEUED = 100;
+
+  // what the server waits for before capturing, polled as
+  // `window.__webstreamerReadiness`
+  const readiness = { extension: false, page: false };
+  Object.defineProperty(window, "__webstreamerReadiness", { value: readiness });
+
+  const state = {
+    version: protocol.version,
+    server: protocol.server,
+    connected: false,
+    // `msg_id` of the last message delivered
+    msg_id: null,
+    scene: null,
+  };
+  const listeners = new Map();
+  const queued = [];
+  let subscription = null;
+  // `seq` of the last message on this connection
+  let seq = 0;
+
+  const emit = (event, message) => {
+    for (const key of [event, "*"]) {
+      for (const callback of listeners.get(key) ?? []) {
+        try {
+          callback(message);
+        } catch (e) {
+          console.error(`webstreamer: ${key} listener failed`, e);
+        }
+      }
+    }
+  };
+
+  const setState = (changes) => {
+    Object.assign(state, changes);
+    emit("state", { ...state });
+  };
+
+  const post = (message) => {
+    window.postMessage({ type: "PAGE_MESSAGE", message }, "*");
+  };
+
+  const deliver = (message) => {
+    if (message.type === "scene") setState({ scene: message.scene });
+    emit(message.type, message);
+  };
+
+  const receive = (message) => {
+    if (message.type === "backlog") {
+      // a restarted server counts from zero again, and its backlog was cut
+      // at the old `msg_id`
+      if (state.msg_id !== null && message.msg_id < state.msg_id) {
+        state.msg_id = null;
+        post({ type: "history", since: 0 });
+        return;
+      }
+      for (const missed of message.messages) {
+        if (state.msg_id === null || missed.msg_id > state.msg_id) {
+          state.msg_id = missed.msg_id;
+          deliver(missed);
+        }
+      }
+      if (state.msg_id === null || message.msg_id > state.msg_id) state.msg_id = message.msg_id;
+      seq = Math.max(seq, message.seq);
+      emit("backlog", message);
+      return;
+    }
+    if (message.seq > seq + 1) post({ type: "history", since: state.msg_id ?? 0 });
+    seq = Math.max(seq, message.seq);
+    if (state.msg_id !== null && message.msg_id <= state.msg_id) return;
+    state.msg_id = message.msg_id;
+    deliver(message);
+  };
+
+  window.addEventListener("message", (event) => {
+    if (event.source !== window) return;
+    const data = event.data;
+    if (data?.type === "CONTENT_READY") {
+      readiness.extension = true;
+    } else if (data?.type === "EXTENSION_STATE") {
+      setState({ connected: data.connected });
+      if (!data.connected) return;
+      seq = 0;
+      // a new connection starts without a subscription and sends nothing
+      // until it gets one or a history request
+      const since = state.msg_id ?? 0;
+      post(subscription ? { ...subscription, since } : { type: "history", since });
+      for (const message of queued.splice(0)) post(message);
+    } else if (data?.type === "EXTENSION") {
+      try {
+        receive(JSON.parse(data.message));
+      } catch (e) {
+        console.error("webstreamer: invalid message", data.message, e);
+      }
+    }
+  });
+
+  const webstreamer = {
+    version: protocol.version,
+    state,
+
+    // Calls `callback` with every message of type `event`, `*` for all of
+    // them or `state` for connection and scene changes. Returns a function
+    // that removes it.
+    on(event, callback) {
+      const known = ["*", "state", ...protocol.events];
+      if (!known.includes(event)) console.warn(`webstreamer: unknown event ${event}`);
+      if (!listeners.has(event)) listeners.set(event, new Set());
+      listeners.get(event).add(callback);
+      return () => listeners.get(event).delete(callback);
+    },
+
+    // Sends `method` with `args` to the server, see the readme's page
+    // messages. Throws for unknown methods and missing arguments.
+    call(method, args = {}) {
+      const fields = protocol.methods[method];
+      if (!fields) throw new Error(`webstreamer: unknown method ${method}`);
+      const missing = fields.required.filter((field) => args[field] === undefined);
+      if (missing.length) {
+        throw new Error(`webstreamer: ${method} needs ${missing.join(", ")}`);
+      }
+      const message = { ...args, type: method };
+      if (method === "subscribe") {
+        // sent again on every connection
+        subscription = message;
+        if (!state.connected) return;
+      }
+      if (state.connected) {
+        post(message);
+      } else {
+        queued.push(message);
+        if (queued.length > MAX_QUEUED) queued.shift();
+      }
+    },
+
+    // Tells the server the page is ready to be captured, see `wait_for_page`.
+    ready() {
+      readiness.page = true;
+    },
+  };
+  window.webstreamer = webstreamer;
+})();
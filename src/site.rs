@@ -0,0 +1,163 @@
+use axum::{
+    Router,
+    http::{HeaderValue, header},
+};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Deserialize;
+use std::{net::SocketAddr, path::Path, time::Duration};
+use tokio::{
+    join,
+    net::TcpListener,
+    spawn,
+    sync::mpsc::{self, Sender},
+    task::JoinHandle,
+    time::timeout,
+};
+use tower_http::{
+    services::{ServeDir, ServeFile},
+    set_header::SetResponseHeaderLayer,
+};
+use tracing::{info, warn};
+
+use crate::control::Control;
+
+/// When the captured tab is reloaded after the site's files change.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReloadMode {
+    /// Right away.
+    Dev,
+    /// At the next scene switch away from it, right away while the output is
+    /// stopped, or after `max_delay`. Tabs in the background reload right away.
+    #[default]
+    Production,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SiteConfig {
+    /// Directory to serve, without one no site is served and `WEBSITE` is
+    /// required.
+    pub dir: Option<String>,
+    /// Address to listen on, local only by default.
+    pub bind: String,
+    pub port: u16,
+    /// Serve `index.html` for paths without a file, for single page apps with
+    /// history routing.
+    pub spa: bool,
+    /// Reload pages of the site when its files change.
+    pub watch: bool,
+    pub reload: ReloadMode,
+    /// Milliseconds without further changes before reloading, so a build
+    /// writing many files reloads once.
+    pub debounce: u64,
+    /// Seconds a production reload waits for a safe moment, 0 waits forever.
+    pub max_delay: u64,
+}
+
+impl Default for SiteConfig {
+    fn default() -> Self {
+        SiteConfig {
+            dir: None,
+            bind: "127.0.0.1".to_string(),
+            port: 8082,
+            spa: false,
+            watch: true,
+            reload: ReloadMode::default(),
+            debounce: 300,
+            max_delay: 0,
+        }
+    }
+}
+
+impl SiteConfig {
+    /// Where Chrome loads the site from, used when `WEBSITE` isn't set.
+    pub fn url(&self) -> String {
+        let host = match self.bind.as_str() {
+            "0.0.0.0" => "127.0.0.1",
+            bind => bind,
+        };
+        format!("http://{}:{}/", host, self.port)
+    }
+}
+
+/// Serves `dir` without caching and sends `Control::SiteChanged` whenever files
+/// in it change. Does nothing without a `dir`.
+pub async fn run_site(config: SiteConfig, control_tx: Sender<Control>) -> JoinHandle<()> {
+    let Some(dir) = config.dir.clone() else {
+        return spawn(async {});
+    };
+    let files = ServeDir::new(&dir).append_index_html_on_directories(true);
+    let app = if config.spa {
+        let index = Path::new(&dir).join("index.html");
+        Router::new().fallback_service(files.fallback(ServeFile::new(index)))
+    } else {
+        Router::new().fallback_service(files)
+    };
+    // reloads have to pick up the changed files
+    let app = app.layer(SetResponseHeaderLayer::overriding(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("no-cache"),
+    ));
+
+    let addr = format!("{}:{}", config.bind, config.port)
+        .parse::<SocketAddr>()
+        .unwrap();
+    let listener = TcpListener::bind(&addr).await.unwrap();
+    info!("serving {} on: http://{}", dir, addr);
+    spawn(async move {
+        let serve = async {
+            if let Err(e) = axum::serve(listener, app).await {
+                warn!("site server stopped: {}", e);
+            }
+        };
+        let watch = async {
+            if config.watch {
+                watch_changes(&dir, Duration::from_millis(config.debounce), control_tx).await;
+            }
+        };
+        join!(serve, watch);
+    })
+}
+
+async fn watch_changes(dir: &str, debounce: Duration, control_tx: Sender<Control>) {
+    let (tx, mut changes) = mpsc::channel(100);
+    let watcher = RecommendedWatcher::new(
+        move |event: notify::Result<Event>| match event {
+            // reads by the site server
+            Ok(event) if event.kind.is_access() => {}
+            Ok(_) => {
+                // a full channel already means a reload
+                let _ = tx.try_send(());
+            }
+            Err(e) => warn!("watching site files failed: {}", e),
+        },
+        notify::Config::default(),
+    );
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            warn!("failed to watch site files: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = watcher.watch(Path::new(dir), RecursiveMode::Recursive) {
+        warn!("failed to watch {}: {}", dir, e);
+        return;
+    }
+    info!("watching {} for changes", dir);
+    while changes.recv().await.is_some() {
+        // wait for the rest of the burst
+        loop {
+            match timeout(debounce, changes.recv()).await {
+                Ok(Some(())) => continue,
+                Ok(None) => return,
+                Err(_) => break,
+            }
+        }
+        info!("site files changed");
+        if control_tx.send(Control::SiteChanged).await.is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::watch;
+
+/// What the admin API reports, each part updated by the task that owns it.
+/// Uses a std mutex since it is never held across an await.
+#[derive(Clone)]
+pub struct Status {
+    inner: Arc<Mutex<StatusInner>>,
+    scene: watch::Sender<Option<String>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StatusInner {
+    pub browser: BrowserStatus,
+    pub extension: ExtensionStatus,
+    pub encoder: EncoderStatus,
+    /// By session index.
+    pub eventsub: BTreeMap<usize, SessionStatus>,
+    /// By token name (`twitch`, `twitch bot`).
+    pub tokens: BTreeMap<String, TokenStatus>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BrowserStatus {
+    /// `starting`, `capturing`, `recovering` or `test-pattern`.
+    pub state: String,
+    pub url: String,
+    /// Crashes, hangs and exits recovered from.
+    pub recoveries: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExtensionStatus {
+    pub connected: bool,
+    pub connections: u64,
+    pub bytes: u64,
+    /// Unix seconds.
+    pub last_data_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EncoderStatus {
+    pub running: bool,
+    /// Whether the RTMP output is started.
+    pub output: bool,
+    pub restarts: u64,
+    /// Restarted captures joined to the running ffmpeg.
+    pub splices: u64,
+    /// The latest `-progress` values from ffmpeg, like `fps`, `bitrate` and `speed`.
+    pub progress: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SessionStatus {
+    pub connected: bool,
+    pub session_id: Option<String>,
+    pub channels: usize,
+    /// Ids of the channels over the eventsub cost limit, which only get chat.
+    pub chat_only: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TokenStatus {
+    pub login: String,
+    /// Unix seconds.
+    pub expires_at: u64,
+}
+
+impl Status {
+    pub fn new() -> Self {
+        Status {
+            inner: Arc::new(Mutex::new(StatusInner::default())),
+            scene: watch::Sender::new(None),
+        }
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut StatusInner)) {
+        f(&mut self.inner.lock().unwrap());
+    }
+
+    pub fn snapshot(&self) -> serde_json::Value {
+        let mut snapshot = serde_json::to_value(&*self.inner.lock().unwrap()).unwrap();
+        snapshot["scene"] = serde_json::json!(*self.scene.borrow());
+        snapshot
+    }
+
+    /// Whether the RTMP output is started, see [`EncoderStatus::output`].
+    pub fn output_started(&self) -> bool {
+        self.inner.lock().unwrap().encoder.output
+    }
+
+    /// Records the scene shown on the page, see [`Status::scenes`].
+    pub fn set_scene(&self, scene: &str) {
+        self.scene.send_replace(Some(scene.to_string()));
+    }
+
+    /// Notifies on every scene switch.
+    pub fn scenes(&self) -> watch::Receiver<Option<String>> {
+        self.scene.subscribe()
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
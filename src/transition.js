@@ -0,0 +1,27 @@
+// Injected into the captured tabs for scene transitions, fades a black
+// overlay in (`opacity` 1) or out (0) over `duration` milliseconds.
+(() => {
+  const fade = (opacity, duration) =>
+    new Promise((resolve) => {
+      let overlay = document.getElementById("webstreamer-transition");
+      if (!overlay) {
+        overlay = document.createElement("div");
+        overlay.id = "webstreamer-transition";
+        Object.assign(overlay.style, {
+          position: "fixed",
+          inset: "0",
+          background: "black",
+          zIndex: "2147483647",
+          pointerEvents: "none",
+          opacity: "0",
+        });
+        document.documentElement.appendChild(overlay);
+      }
+      overlay.style.transition = `opacity ${duration}ms linear`;
+      // apply the current opacity before transitioning from it
+      overlay.getBoundingClientRect();
+      overlay.style.opacity = String(opacity);
+      setTimeout(resolve, duration);
+    });
+  Object.defineProperty(window, "__webstreamerFade", { value: fade });
+})();
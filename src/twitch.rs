@@ -1,224 +1,599 @@
+mod alerts;
+mod chat;
+mod commands;
 mod event_ws;
-use event_ws::EventWebsocketClient;
-use reqwest::Client;
-use serde_json::json;
-use std::{
-    collections::HashMap,
-    io::{BufRead, BufReader, Write},
-    process::{Command, Stdio},
-    sync::Arc,
-    time::SystemTime,
+mod metadata;
+mod moderation;
+mod ordering;
+mod polls;
+mod rewards;
+mod sender;
+use crate::{
+    config::Config,
+    control::Control,
+    metrics::MeteredClient,
+    page::PageSender,
+    plays::{Plays, PlaysConfig},
+    protocol::PageMessage,
+    scenes::MAIN_SCENE,
+    status::{Status, TokenStatus, unix_now},
+};
+pub use alerts::AlertsConfig;
+use alerts::{Alert, AlertControl, AlertQueue};
+use chat::ChatEnricher;
+pub use commands::CommandsConfig;
+use commands::{CommandRouter, Dispatch};
+use event_ws::{
+    CHANNELS_PER_SESSION, EventWebsocketClient, MAX_SESSIONS, budget_channels, event_channel,
 };
+use futures::future::join_all;
+pub use metadata::{Metadata, MetadataConfig, StreamAction};
+use moderation::Moderation;
+pub use moderation::ModerationConfig;
+pub use ordering::OrderingConfig;
+use ordering::{Notification, run_ordered};
+use polls::{PollEngine, PollRequest};
+pub use polls::{PollsConfig, Weight};
+use rewards::Rewards;
+pub use rewards::RewardsConfig;
+pub use sender::ChatConfig;
+use sender::ChatSender;
+use serde_json::json;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 use tokio::{
     join, spawn,
     sync::{
-        Mutex,
+        Mutex, mpsc,
         mpsc::{Receiver, Sender},
     },
     task::JoinHandle,
     time::sleep,
 };
-use tokio_tungstenite::tungstenite::Bytes;
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
 use twitch_api::{
     HelixClient, TWITCH_EVENTSUB_WEBSOCKET_URL,
     client::ClientDefault,
     eventsub::{Event, Message, Payload},
-    helix::{Scope, users::User},
+    helix::Scope,
     twitch_oauth2::{ClientSecret, DeviceUserTokenBuilder, TwitchToken, UserToken},
-    types::UserId,
+    types::{MsgId, Timestamp, UserId, UserName},
 };
 
 pub async fn run_twitch(
     twitch_client_id: &str,
     twitch_client_secret: &str,
-    twitch_rtmp_url: &str,
-    stream_rx: Receiver<Bytes>,
-    ws_tx: Sender<String>,
-) -> (JoinHandle<()>, JoinHandle<()>) {
-    let server = TwitchServer::new(twitch_client_id, twitch_client_secret).await;
-    let event_listener = spawn(async move { server.run_event_listener(ws_tx).await });
-    let twitch_rtmp_url = twitch_rtmp_url.to_string();
-    let stream = spawn(async move { run_stream(&twitch_rtmp_url, stream_rx).await });
-    (event_listener, stream)
+    config: &Config,
+    page: PageSender,
+    page_rx: Receiver<PageMessage>,
+    control_tx: Sender<Control>,
+    status: Status,
+) -> (JoinHandle<()>, Arc<Metadata>) {
+    let server = TwitchServer::new(twitch_client_id, twitch_client_secret, config, status).await;
+    let commands = CommandRouter::new(config.commands.clone(), server.chat_sender.clone());
+    let moderation = Moderation::new(
+        config.moderation.clone(),
+        server.helix_client.clone(),
+        server.user_token.clone(),
+    );
+    let (alerts, _) = AlertQueue::spawn(config.alerts.clone(), page.clone());
+    let metadata = server.metadata.clone();
+    let handle = spawn(async move {
+        server
+            .run_event_listener(page, page_rx, commands, moderation, alerts, control_tx)
+            .await
+    });
+    (handle, metadata)
 }
 
 struct TwitchServer {
     user_token: Arc<Mutex<UserToken>>,
-    helix_client: HelixClient<'static, Client>,
+    bot_token: Option<Arc<Mutex<UserToken>>>,
+    helix_client: HelixClient<'static, MeteredClient>,
+    chat_sender: ChatSender,
+    channels: Vec<String>,
+    polls: PollsConfig,
+    plays: PlaysConfig,
+    rewards: RewardsConfig,
+    metadata: Arc<Metadata>,
+    ordering: OrderingConfig,
+    /// Scene to show during ad breaks.
+    ad_break: Option<String>,
+    status: Status,
 }
 
 impl TwitchServer {
-    pub async fn new(twitch_client_id: &str, twitch_client_secret: &str) -> Self {
-        let client: HelixClient<reqwest::Client> = twitch_api::HelixClient::with_client(
+    pub async fn new(
+        twitch_client_id: &str,
+        twitch_client_secret: &str,
+        config: &Config,
+        status: Status,
+    ) -> Self {
+        let chat_config = &config.chat;
+        let client: HelixClient<MeteredClient> = twitch_api::HelixClient::with_client(
             ClientDefault::default_client_with_name(Some("webstreamer".parse().unwrap())).unwrap(),
         );
-        let mut builder = DeviceUserTokenBuilder::new(
-            twitch_client_id,
-            vec![Scope::UserReadChat, Scope::UserWriteChat],
+        let mut scopes = vec![Scope::UserReadChat, Scope::UserWriteChat];
+        if chat_config.bot {
+            scopes.push(Scope::ChannelBot);
+        }
+        scopes.extend([
+            Scope::ModeratorReadFollowers,
+            Scope::ChannelReadSubscriptions,
+            Scope::BitsRead,
+            Scope::ChannelReadRedemptions,
+            Scope::ChannelManageBroadcast,
+            Scope::ClipsEdit,
+        ]);
+        if !config.rewards.list.is_empty() {
+            scopes.push(Scope::ChannelManageRedemptions);
+        }
+        if config.polls.twitch {
+            scopes.push(Scope::ChannelManagePolls);
+        }
+        if config.moderation.moderates_twitch() {
+            scopes.push(Scope::ModeratorManageChatMessages);
+            scopes.push(Scope::ModeratorManageBannedUsers);
+        }
+        if config.scenes.ad_break.is_some() {
+            scopes.push(Scope::ChannelReadAds);
+        }
+        let user_token = Arc::new(Mutex::new(
+            authenticate(
+                &client,
+                "twitch",
+                twitch_client_id,
+                twitch_client_secret,
+                scopes,
+            )
+            .await,
+        ));
+        let bot_token = if chat_config.bot {
+            let scopes = vec![Scope::UserWriteChat, Scope::UserBot];
+            let token = authenticate(
+                &client,
+                "twitch bot",
+                twitch_client_id,
+                twitch_client_secret,
+                scopes,
+            )
+            .await;
+            Some(Arc::new(Mutex::new(token)))
+        } else {
+            None
+        };
+        let (chat_sender, _) = ChatSender::spawn(
+            client.clone(),
+            bot_token.clone().unwrap_or(user_token.clone()),
+            chat_config.rate_limit,
         );
-        let code = builder.start(&client).await.unwrap();
-        info!("authenticate twitch: {}", code.verification_uri);
-        let mut user_token = builder.wait_for_code(&client, sleep).await.unwrap();
-        user_token.set_secret(Some(ClientSecret::new(twitch_client_secret.to_string())));
+        let metadata = Arc::new(Metadata::new(
+            config.metadata.clone(),
+            client.clone(),
+            user_token.clone(),
+        ));
         TwitchServer {
-            user_token: Arc::new(Mutex::new(user_token)),
+            user_token,
+            bot_token,
             helix_client: client,
+            chat_sender,
+            channels: config.channels.clone(),
+            polls: config.polls.clone(),
+            plays: config.plays.clone(),
+            rewards: config.rewards.clone(),
+            metadata,
+            ordering: config.ordering.clone(),
+            ad_break: config.scenes.ad_break.clone(),
+            status,
         }
     }
 
-    pub async fn run_event_listener(&self, ws_tx: Sender<String>) {
-        type CachedUser = (User, SystemTime);
-
-        let user_cache = Arc::new(Mutex::new(HashMap::<UserId, CachedUser>::new()));
-        let ws = EventWebsocketClient {
-            session_id: None,
-            token: self.user_token.clone(),
-            client: self.helix_client.clone(),
-            chats: vec![self.user_token.lock().await.user_id.clone()],
-            connect_url: TWITCH_EVENTSUB_WEBSOCKET_URL.to_string(),
+    pub async fn run_event_listener(
+        &self,
+        page: PageSender,
+        mut page_rx: Receiver<PageMessage>,
+        commands: CommandRouter,
+        moderation: Moderation,
+        alerts: AlertQueue,
+        control_tx: Sender<Control>,
+    ) {
+        let chat = Arc::new(ChatEnricher::new(
+            self.helix_client.clone(),
+            self.user_token.clone(),
+        ));
+        let commands = Arc::new(commands);
+        let moderation = Arc::new(moderation);
+        let metadata = self.metadata.clone();
+        let (polls, _) = PollEngine::spawn(
+            self.polls.clone(),
+            page.clone(),
+            self.helix_client.clone(),
+            self.user_token.clone(),
+        );
+        let (rewards, _) = Rewards::spawn(
+            self.rewards.clone(),
+            page.clone(),
+            self.helix_client.clone(),
+            self.user_token.clone(),
+        );
+        let plays = self
+            .plays
+            .enabled
+            .then(|| Plays::spawn(self.plays.clone(), page.clone(), control_tx.clone()).0);
+        let (broadcaster_id, broadcaster_login) = {
+            let token = self.user_token.lock().await;
+            (token.user_id.clone(), token.login.clone())
+        };
+        let channels = Arc::new(
+            self.resolve_channels(&broadcaster_id, &broadcaster_login)
+                .await,
+        );
+        let refresh_tokens = async {
+            let bot = async {
+                if let Some(bot_token) = &self.bot_token {
+                    keep_token_fresh("twitch bot", bot_token, &self.helix_client, &self.status)
+                        .await;
+                }
+            };
+            let user =
+                keep_token_fresh("twitch", &self.user_token, &self.helix_client, &self.status);
+            join!(user, bot);
         };
-        let refresh_token = async move {
-            let token = self.user_token.clone();
-            let client = self.helix_client.clone();
-            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
+        let scene_changes = async {
+            let mut scenes = self.status.scenes();
+            // the current scene first, rewards handle it once they are synced
             loop {
-                interval.tick().await;
-                let mut token = token.lock().await;
-                if token.expires_in() < std::time::Duration::from_secs(60) {
-                    token.refresh_token(&self.helix_client).await.unwrap();
+                let scene = scenes.borrow_and_update().clone();
+                if let Some(scene) = scene {
+                    rewards.scene(&scene).await;
+                    metadata.set_scene(&scene).await;
+                }
+                if scenes.changed().await.is_err() {
+                    break;
+                }
+            }
+        };
+        let page_messages = async {
+            while let Some(message) = page_rx.recv().await {
+                match message {
+                    PageMessage::ChatSend {
+                        text,
+                        reply_to,
+                        broadcaster_id: channel_id,
+                    } => {
+                        let broadcaster_id = channel_id
+                            .map(UserId::from)
+                            .unwrap_or(broadcaster_id.clone());
+                        match reply_to {
+                            Some(parent) => {
+                                self.chat_sender
+                                    .reply(&broadcaster_id, &MsgId::from(parent), &text)
+                            }
+                            None => self.chat_sender.send(&broadcaster_id, &text),
+                        }
+                    }
+                    PageMessage::AlertDone { id } => alerts.control(AlertControl::Done(id)).await,
+                    PageMessage::AlertPause {} => alerts.control(AlertControl::Pause).await,
+                    PageMessage::AlertResume {} => alerts.control(AlertControl::Resume).await,
+                    PageMessage::AlertSkip {} => alerts.control(AlertControl::Skip).await,
+                    PageMessage::AlertReplay { id } => {
+                        alerts.control(AlertControl::Replay(id)).await
+                    }
+                    PageMessage::PollStart {
+                        title,
+                        options,
+                        duration,
+                        changeable,
+                        weight,
+                        twitch,
+                    } => {
+                        let request = PollRequest {
+                            title,
+                            options,
+                            duration,
+                            changeable,
+                            weight,
+                            twitch,
+                        };
+                        polls.start(request).await
+                    }
+                    PageMessage::PollEnd {} => polls.end().await,
+                    PageMessage::Marker { description } => {
+                        let action = StreamAction::Marker(description);
+                        if let Some(message) = metadata.run(action).await {
+                            page.send(message).await;
+                        }
+                    }
+                    PageMessage::Clip {} => {
+                        if let Some(message) = metadata.run(StreamAction::Clip).await {
+                            page.send(message).await;
+                        }
+                    }
+                    PageMessage::RedemptionFulfill { id } => rewards.resolve(id.into(), true).await,
+                    PageMessage::RedemptionCancel { id } => rewards.resolve(id.into(), false).await,
+                    PageMessage::SwitchScene { scene } => {
+                        control_tx.send(Control::SwitchScene(scene)).await.unwrap()
+                    }
+                    PageMessage::PlaysMode { mode } => match &plays {
+                        Some(plays) => plays.set_mode(mode).await,
+                        None => warn!("plays-mode without plays configured"),
+                    },
+                    // answered by the websocket itself
+                    PageMessage::History { .. } | PageMessage::Subscribe { .. } => {
+                        debug!("unhandled page message: {:?}", message)
+                    }
                 }
-                token.validate_token(&client).await.unwrap();
             }
         };
-        let ws = ws.run(|e, ts| {
-            let ws_tx = ws_tx.clone();
-            let client = self.helix_client.clone();
-            let token = self.user_token.clone();
-            let user_cache = user_cache.clone();
+        let on_event = |mut e: Event, ts: Timestamp| {
+            let page = page.clone();
+            let channels = channels.clone();
+            let broadcaster_id = broadcaster_id.clone();
+            let chat = chat.clone();
+            let commands = commands.clone();
+            let moderation = moderation.clone();
+            let alerts = alerts.clone();
+            let polls = polls.clone();
+            let plays = plays.clone();
+            let rewards = rewards.clone();
+            let metadata = metadata.clone();
+            let control_tx = control_tx.clone();
+            let status = self.status.clone();
+            let ad_break = self.ad_break.clone();
             async move {
                 info!("ws event: {:?}, timestamp: {:?}", e, ts);
-                let message = json!({
-                    "type": "twitch-event",
-                    "event": e,
-                    "timestamp": ts
-                });
-                ws_tx.send(message.to_string()).await.unwrap();
+                // filter before anything is sent, so blocked text never reaches the page
+                let allowed = match &e {
+                    Event::ChannelChatMessageV1(Payload {
+                        message: Message::Notification(payload),
+                        ..
+                    }) => moderation.allow(payload).await,
+                    Event::ChannelChatNotificationV1(Payload {
+                        message: Message::Notification(payload),
+                        ..
+                    }) => moderation.allow_text(&payload.message.text),
+                    _ => true,
+                };
+                if !allowed {
+                    return;
+                }
+                moderation.scrub(&mut e);
+                let channel_id = event_channel(&e);
+                let channel = channel_id
+                    .as_ref()
+                    .map(|id| json!({ "id": id, "login": channels.get(id) }));
+                let reward_id = match &e {
+                    Event::ChannelPointsCustomRewardRedemptionAddV1(Payload {
+                        message: Message::Notification(payload),
+                        ..
+                    }) => Some(payload.reward.id.clone()),
+                    _ => None,
+                };
+                // chat messages only reach the page enriched, as `twitch-chat` below
+                if !matches!(e, Event::ChannelChatMessageV1(_)) {
+                    let message = json!({
+                        "type": "twitch-event",
+                        "event_type": e.subscription().ok().map(|s| s.type_),
+                        "channel": channel,
+                        "reward_id": reward_id,
+                        "event": e,
+                        "timestamp": ts
+                    });
+                    page.send(message).await;
+                }
+                if channel_id.as_ref() == Some(&broadcaster_id)
+                    && let Some(alert) = Alert::from_event(&e)
+                {
+                    alerts.push(alert).await;
+                }
+                if channel_id.as_ref() == Some(&broadcaster_id)
+                    && let Event::StreamOnlineV1(Payload {
+                        message: Message::Notification(_),
+                        ..
+                    }) = &e
+                {
+                    metadata.apply().await;
+                }
+                if channel_id.as_ref() == Some(&broadcaster_id)
+                    && let Event::ChannelPointsCustomRewardRedemptionAddV1(Payload {
+                        message: Message::Notification(payload),
+                        ..
+                    }) = &e
+                {
+                    rewards.redeemed(payload).await;
+                }
+                if channel_id.as_ref() == Some(&broadcaster_id)
+                    && let Some((id, choices)) = match &e {
+                        Event::ChannelPollProgressV1(Payload {
+                            message: Message::Notification(payload),
+                            ..
+                        }) => Some((&payload.id, &payload.choices)),
+                        Event::ChannelPollEndV1(Payload {
+                            message: Message::Notification(payload),
+                            ..
+                        }) => Some((&payload.id, &payload.choices)),
+                        _ => None,
+                    }
+                {
+                    polls.twitch_votes(id.clone(), choices.clone()).await;
+                }
+                if channel_id.as_ref() == Some(&broadcaster_id)
+                    && let Some(scene) = ad_break
+                    && let Event::ChannelAdBreakBeginV1(Payload {
+                        message: Message::Notification(payload),
+                        ..
+                    }) = &e
+                {
+                    let previous = status
+                        .scenes()
+                        .borrow()
+                        .clone()
+                        .unwrap_or(MAIN_SCENE.to_string());
+                    let duration = Duration::from_secs(payload.duration_seconds.max(0) as u64);
+                    info!("ad break for {:?}, showing scene {}", duration, scene);
+                    control_tx.send(Control::SwitchScene(scene)).await.unwrap();
+                    let control_tx = control_tx.clone();
+                    spawn(async move {
+                        sleep(duration).await;
+                        info!("ad break over, back to scene {}", previous);
+                        let _ = control_tx.send(Control::SwitchScene(previous)).await;
+                    });
+                }
                 if let Event::ChannelChatMessageV1(Payload {
                     message: Message::Notification(payload),
                     ..
                 }) = e
                 {
                     info!("message from user_id: {}", payload.chatter_user_id);
-                    let id = payload.chatter_user_id;
-                    let mut user_cache = user_cache.lock().await;
-
-                    let now = SystemTime::now();
-                    let cache_timeout = std::time::Duration::from_secs(30 * 60); // 30 minutes
-
-                    let is_cache_valid = user_cache
-                        .get(&id)
-                        .map(|(_, cached_time)| {
-                            now.duration_since(*cached_time).unwrap_or(cache_timeout)
-                                < cache_timeout
-                        })
-                        .unwrap_or(false);
-
-                    let user = if is_cache_valid {
-                        user_cache.get(&id).unwrap().0.clone()
-                    } else {
-                        let log_message = if user_cache.contains_key(&id) {
-                            "cache expired for user_id"
-                        } else {
-                            "fetching user info"
-                        };
-                        info!("{}: {}", log_message, id);
-
-                        let token = token.lock().await;
-                        let user = client
-                            .get_user_from_id(&id, &*token)
-                            .await
-                            .unwrap()
-                            .unwrap();
+                    let mut message = chat.enrich(&payload).await;
+                    message["channel"] = json!(channel);
+                    message["timestamp"] = json!(ts);
+                    page.send(message).await;
+                    // other channels' chats can't control this stream
+                    if channel_id != Some(broadcaster_id) {
+                        return;
+                    }
+                    polls.vote(&payload).await;
+                    if let Some(plays) = &plays {
+                        plays.chat(payload.chatter_user_login.as_str(), &payload.message.text);
+                    }
+                    match commands.route(&payload).await {
+                        Some(Dispatch::Page(mut command)) => {
+                            command["channel"] = json!(channel);
+                            page.send(command).await
+                        }
+                        Some(Dispatch::Control(control)) => control_tx.send(control).await.unwrap(),
+                        Some(Dispatch::Stream(action)) => {
+                            if let Some(message) = metadata.run(action).await {
+                                page.send(message).await
+                            }
+                        }
+                        None => {}
+                    }
+                }
+            }
+        };
 
-                        user_cache.insert(id.clone(), (user.clone(), now));
-                        user
-                    };
+        // in config order, so the first channels listed keep their stream events
+        let mut ids: Vec<UserId> = channels.keys().cloned().collect();
+        ids.sort_by_key(|id| {
+            let login = channels[id].as_str();
+            self.channels
+                .iter()
+                .position(|c| c.eq_ignore_ascii_case(login))
+        });
+        let (stream_events, chat_only) = budget_channels(&ids, &broadcaster_id);
+        if !chat_only.is_empty() {
+            let logins: Vec<&str> = chat_only.iter().map(|id| channels[id].as_str()).collect();
+            warn!(
+                "over the eventsub cost limit, only subscribing to chat for: {}",
+                logins.join(", ")
+            );
+        }
+        if ids.len() > CHANNELS_PER_SESSION * MAX_SESSIONS {
+            warn!(
+                "can only watch {} channels, ignoring the rest",
+                CHANNELS_PER_SESSION * MAX_SESSIONS
+            );
+        }
+        let (notification_tx, notification_rx) = mpsc::channel::<Notification>(100);
+        let sessions = ids
+            .chunks(CHANNELS_PER_SESSION)
+            .take(MAX_SESSIONS)
+            .enumerate()
+            .map(|(index, chats)| {
+                let ws = EventWebsocketClient {
+                    index,
+                    status: self.status.clone(),
+                    session_id: None,
+                    token: self.user_token.clone(),
+                    client: self.helix_client.clone(),
+                    chats: chats.to_vec(),
+                    stream_events: stream_events.clone(),
+                    alert_channel: chats
+                        .contains(&broadcaster_id)
+                        .then(|| broadcaster_id.clone()),
+                    ad_breaks: self.ad_break.is_some(),
+                    polls: self.polls.twitch,
+                    connect_url: TWITCH_EVENTSUB_WEBSOCKET_URL.to_string(),
+                };
+                let notification_tx = notification_tx.clone();
+                ws.run(move |notification| {
+                    let notification_tx = notification_tx.clone();
+                    async move { notification_tx.send(notification).await.unwrap() }
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(notification_tx);
+        join!(
+            join_all(sessions),
+            run_ordered(self.ordering.clone(), notification_rx, on_event),
+            refresh_tokens,
+            page_messages,
+            scene_changes
+        );
+    }
 
-                    let message = json!({
-                        "type": "twitch-user",
-                        "user": user,
-                    });
-                    ws_tx.send(message.to_string()).await.unwrap();
+    /// The token owner's channel plus the configured logins, by id.
+    async fn resolve_channels(
+        &self,
+        broadcaster_id: &UserId,
+        broadcaster_login: &UserName,
+    ) -> HashMap<UserId, UserName> {
+        let mut channels = HashMap::from([(broadcaster_id.clone(), broadcaster_login.clone())]);
+        let token = self.user_token.lock().await;
+        for login in &self.channels {
+            match self
+                .helix_client
+                .get_user_from_login(login.as_str(), &*token)
+                .await
+            {
+                Ok(Some(user)) => {
+                    info!("watching channel {} ({})", user.login, user.id);
+                    channels.insert(user.id, user.login);
                 }
+                Ok(None) => warn!("unknown channel: {}", login),
+                Err(e) => warn!("failed to look up channel {}: {}", login, e),
             }
-        });
-        join!(ws, refresh_token);
+        }
+        channels
     }
 }
 
-pub async fn run_stream(twitch_rtmp_url: &str, mut stream_rx: Receiver<Bytes>) {
-    info!("starting ffmpeg process");
-    let mut ffmpeg = Command::new("ffmpeg")
-        .args([
-            "-i",
-            "-",
-            "-vsync",
-            "cfr",
-            "-c:v",
-            "h264_nvenc",
-            "-rc",
-            "cbr",
-            "-r",
-            "60",
-            "-s",
-            "1280x720",
-            "-b:v",
-            "4500k",
-            "-maxrate",
-            "4500k",
-            "-bufsize",
-            "9000k",
-            "-pix_fmt",
-            "yuv420p",
-            "-profile:v",
-            "high",
-            "-c:a",
-            "aac",
-            "-b:a",
-            "160k",
-            "-ar",
-            "48000",
-            "-ac",
-            "2",
-            "-f",
-            "flv",
-            twitch_rtmp_url,
-        ])
-        .stdin(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .unwrap();
-
-    let mut stdin = ffmpeg.stdin.take().unwrap();
-    let stderr = ffmpeg.stderr.take().unwrap();
+async fn authenticate(
+    client: &HelixClient<'static, MeteredClient>,
+    name: &str,
+    twitch_client_id: &str,
+    twitch_client_secret: &str,
+    scopes: Vec<Scope>,
+) -> UserToken {
+    let mut builder = DeviceUserTokenBuilder::new(twitch_client_id, scopes);
+    let code = builder.start(client).await.unwrap();
+    info!("authenticate {}: {}", name, code.verification_uri);
+    let mut user_token = builder.wait_for_code(client, sleep).await.unwrap();
+    user_token.set_secret(Some(ClientSecret::new(twitch_client_secret.to_string())));
+    user_token
+}
 
-    spawn(async move {
-        let reader = BufReader::new(stderr);
-        for line in reader.lines() {
-            match line {
-                Ok(line) => info!("ffmpeg: {}", line),
-                Err(e) => warn!("Error reading ffmpeg stderr: {}", e),
-            }
+async fn keep_token_fresh(
+    name: &str,
+    token: &Mutex<UserToken>,
+    client: &HelixClient<'static, MeteredClient>,
+    status: &Status,
+) {
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
+    loop {
+        interval.tick().await;
+        let mut token = token.lock().await;
+        if token.expires_in() < std::time::Duration::from_secs(60) {
+            token.refresh_token(client).await.unwrap();
         }
-    });
-
-    while let Some(data) = stream_rx.recv().await {
-        stdin.write_all(&data).unwrap();
-    }
-    drop(stdin);
-    match ffmpeg.wait() {
-        Ok(status) => info!("ffmpeg process exited with status: {}", status),
-        Err(e) => warn!("failed to wait for ffmpeg process: {}", e),
+        token.validate_token(client).await.unwrap();
+        let token_status = TokenStatus {
+            login: token.login.to_string(),
+            expires_at: unix_now() + token.expires_in().as_secs(),
+        };
+        status.update(|s| {
+            s.tokens.insert(name.to_string(), token_status);
+        });
     }
 }
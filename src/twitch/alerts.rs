@@ -0,0 +1,360 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::{collections::HashMap, time::Duration};
+use tokio::{
+    select, spawn,
+    sync::mpsc,
+    task::JoinHandle,
+    time::{Instant, sleep_until},
+};
+use tracing::{debug, info};
+use twitch_api::eventsub::{Event, Message, Payload};
+
+use crate::page::PageSender;
+
+/// How many finished alerts are kept for replay.
+const REPLAY_HISTORY: usize = 20;
+/// Gift alerts from the same gifter within this window are merged, also into
+/// the one that is showing.
+const GIFT_MERGE_WINDOW: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AlertsConfig {
+    /// Per alert kind (`follow`, `subscription`, `resub`, `gift`, `cheer`,
+    /// `raid`, `redemption`), overriding the defaults.
+    pub kinds: HashMap<String, AlertKindConfig>,
+    /// Seconds to wait for the page to finish an alert before moving on.
+    pub timeout: u64,
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        AlertsConfig {
+            kinds: HashMap::new(),
+            timeout: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct AlertKindConfig {
+    /// Higher is shown first.
+    pub priority: u32,
+    /// Minimum seconds on screen, even if the page finishes sooner.
+    pub duration: u64,
+}
+
+impl AlertsConfig {
+    fn kind(&self, kind: &str) -> AlertKindConfig {
+        if let Some(config) = self.kinds.get(kind) {
+            return *config;
+        }
+        let priority = match kind {
+            "raid" => 50,
+            "gift" => 40,
+            "cheer" | "subscription" | "resub" => 30,
+            "redemption" => 20,
+            _ => 10,
+        };
+        AlertKindConfig {
+            priority,
+            duration: 5,
+        }
+    }
+}
+
+/// A notification normalized for display.
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub kind: &'static str,
+    pub user: Value,
+    pub amount: Option<i64>,
+    pub message: Option<String>,
+    pub extra: Value,
+}
+
+impl Alert {
+    /// Alerts for the events that have one. Gifted subscriptions are left out,
+    /// they are covered by the gift alert.
+    pub fn from_event(event: &Event) -> Option<Alert> {
+        let alert = match event {
+            Event::ChannelFollowV2(Payload {
+                message: Message::Notification(p),
+                ..
+            }) => Alert {
+                kind: "follow",
+                user: user(&p.user_id, &p.user_login, &p.user_name),
+                amount: None,
+                message: None,
+                extra: json!({}),
+            },
+            Event::ChannelSubscribeV1(Payload {
+                message: Message::Notification(p),
+                ..
+            }) if !p.is_gift => Alert {
+                kind: "subscription",
+                user: user(&p.user_id, &p.user_login, &p.user_name),
+                amount: None,
+                message: None,
+                extra: json!({ "tier": p.tier }),
+            },
+            Event::ChannelSubscriptionMessageV1(Payload {
+                message: Message::Notification(p),
+                ..
+            }) => Alert {
+                kind: "resub",
+                user: user(&p.user_id, &p.user_login, &p.user_name),
+                amount: Some(p.cumulative_months),
+                message: Some(p.message.text.clone()).filter(|m| !m.is_empty()),
+                extra: json!({ "tier": p.tier, "streak_months": p.streak_months }),
+            },
+            Event::ChannelSubscriptionGiftV1(Payload {
+                message: Message::Notification(p),
+                ..
+            }) => Alert {
+                kind: "gift",
+                user: user(&p.user_id, &p.user_login, &p.user_name),
+                amount: Some(p.total),
+                message: None,
+                extra: json!({ "tier": p.tier, "anonymous": p.is_anonymous }),
+            },
+            Event::ChannelCheerV1(Payload {
+                message: Message::Notification(p),
+                ..
+            }) => Alert {
+                kind: "cheer",
+                user: user(&p.user_id, &p.user_login, &p.user_name),
+                amount: Some(p.bits),
+                message: Some(p.message.clone()).filter(|m| !m.is_empty()),
+                extra: json!({ "anonymous": p.is_anonymous }),
+            },
+            Event::ChannelRaidV1(Payload {
+                message: Message::Notification(p),
+                ..
+            }) => Alert {
+                kind: "raid",
+                user: user(
+                    &p.from_broadcaster_user_id,
+                    &p.from_broadcaster_user_login,
+                    &p.from_broadcaster_user_name,
+                ),
+                amount: Some(p.viewers),
+                message: None,
+                extra: json!({}),
+            },
+            Event::ChannelPointsCustomRewardRedemptionAddV1(Payload {
+                message: Message::Notification(p),
+                ..
+            }) => Alert {
+                kind: "redemption",
+                user: user(&p.user_id, &p.user_login, &p.user_name),
+                amount: Some(p.reward.cost),
+                message: Some(p.user_input.clone()).filter(|m| !m.is_empty()),
+                extra: json!({
+                    "redemption_id": p.id,
+                    "reward_id": p.reward.id,
+                    "reward_title": p.reward.title,
+                }),
+            },
+            _ => return None,
+        };
+        Some(alert)
+    }
+}
+
+#[derive(Debug)]
+pub enum AlertControl {
+    /// The page finished showing the alert with this id.
+    Done(u64),
+    Pause,
+    Resume,
+    Skip,
+    /// Show a finished alert again, the latest one if no id is given.
+    Replay(Option<u64>),
+}
+
+enum Input {
+    Alert(Alert),
+    Control(AlertControl),
+}
+
+struct Queued {
+    id: u64,
+    alert: Alert,
+    priority: u32,
+    queued_at: Instant,
+}
+
+struct Showing {
+    queued: Queued,
+    started: Instant,
+    done: bool,
+}
+
+/// Releases alerts to the page one at a time, highest priority first, each
+/// until the page acknowledges it with `alert-done` (but at least its
+/// minimum duration).
+#[derive(Clone)]
+pub struct AlertQueue {
+    tx: mpsc::Sender<Input>,
+}
+
+impl AlertQueue {
+    pub fn spawn(config: AlertsConfig, page: PageSender) -> (Self, JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(100);
+        let handle = spawn(run(config, page, rx));
+        (AlertQueue { tx }, handle)
+    }
+
+    pub async fn push(&self, alert: Alert) {
+        self.tx.send(Input::Alert(alert)).await.unwrap();
+    }
+
+    pub async fn control(&self, control: AlertControl) {
+        self.tx.send(Input::Control(control)).await.unwrap();
+    }
+}
+
+async fn run(config: AlertsConfig, page: PageSender, mut rx: mpsc::Receiver<Input>) {
+    let mut next_id = 0;
+    let mut queue = Vec::<Queued>::new();
+    let mut showing: Option<Showing> = None;
+    let mut history = Vec::<Queued>::new();
+    let mut paused = false;
+    let timeout = Duration::from_secs(config.timeout);
+
+    loop {
+        if showing.is_none()
+            && !paused
+            && let Some(index) = (0..queue.len()).max_by_key(|&i| {
+                // highest priority, then oldest
+                (queue[i].priority, std::cmp::Reverse(queue[i].queued_at))
+            })
+        {
+            let queued = queue.remove(index);
+            info!("showing alert {}: {}", queued.id, queued.alert.kind);
+            page.send(alert_message(&queued)).await;
+            showing = Some(Showing {
+                queued,
+                started: Instant::now(),
+                done: false,
+            });
+        }
+
+        let deadline = showing.as_ref().map(|s| {
+            let duration = Duration::from_secs(config.kind(s.queued.alert.kind).duration);
+            if s.done {
+                s.started + duration
+            } else {
+                s.started + timeout.max(duration)
+            }
+        });
+
+        select! {
+            input = rx.recv() => match input {
+                None => break,
+                Some(Input::Alert(alert)) => {
+                    // merge bursts of gifts from the same gifter into one alert
+                    if let Some(existing) = queue.iter_mut().find(|q| same_gift_burst(q, &alert)) {
+                        merge_gifts(existing, &alert);
+                        continue;
+                    }
+                    if let Some(s) = showing.as_mut().filter(|s| same_gift_burst(&s.queued, &alert)) {
+                        merge_gifts(&mut s.queued, &alert);
+                        let mut update = alert_message(&s.queued);
+                        update["type"] = json!("alert-update");
+                        page.send(update).await;
+                        continue;
+                    }
+                    next_id += 1;
+                    queue.push(Queued {
+                        id: next_id,
+                        priority: config.kind(alert.kind).priority,
+                        alert,
+                        queued_at: Instant::now(),
+                    });
+                }
+                Some(Input::Control(control)) => {
+                    info!("alert control: {:?}", control);
+                    match control {
+                        AlertControl::Done(id) => {
+                            if let Some(s) = showing.as_mut().filter(|s| s.queued.id == id) {
+                                s.done = true;
+                            }
+                        }
+                        AlertControl::Pause => paused = true,
+                        AlertControl::Resume => paused = false,
+                        AlertControl::Skip => {
+                            if let Some(s) = showing.take() {
+                                page.send(json!({ "type": "alert-cancel", "id": s.queued.id })).await;
+                                finish(&mut history, s.queued);
+                            }
+                        }
+                        AlertControl::Replay(id) => {
+                            let past = match id {
+                                Some(id) => history.iter().find(|q| q.id == id),
+                                None => history.last(),
+                            };
+                            if let Some(past) = past {
+                                next_id += 1;
+                                queue.push(Queued {
+                                    id: next_id,
+                                    alert: past.alert.clone(),
+                                    // ahead of everything that's waiting
+                                    priority: u32::MAX,
+                                    queued_at: Instant::now(),
+                                });
+                            }
+                        }
+                    }
+                }
+            },
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                let s = showing.take().unwrap();
+                if !s.done {
+                    debug!("alert {} timed out", s.queued.id);
+                }
+                finish(&mut history, s.queued);
+            }
+        }
+    }
+}
+
+fn same_gift_burst(queued: &Queued, alert: &Alert) -> bool {
+    alert.kind == "gift"
+        && queued.alert.kind == "gift"
+        && queued.alert.user == alert.user
+        && queued.queued_at.elapsed() < GIFT_MERGE_WINDOW
+}
+
+fn merge_gifts(queued: &mut Queued, alert: &Alert) {
+    let total = queued.alert.amount.unwrap_or(0) + alert.amount.unwrap_or(0);
+    debug!("merging gift alert {} to {} gifts", queued.id, total);
+    queued.alert.amount = Some(total);
+}
+
+fn user(id: impl Serialize, login: impl Serialize, name: impl Serialize) -> Value {
+    json!({ "id": id, "login": login, "name": name })
+}
+
+fn finish(history: &mut Vec<Queued>, queued: Queued) {
+    history.push(queued);
+    if history.len() > REPLAY_HISTORY {
+        history.remove(0);
+    }
+}
+
+fn alert_message(queued: &Queued) -> Value {
+    let alert = &queued.alert;
+    json!({
+        "type": "alert",
+        "id": queued.id,
+        "kind": alert.kind,
+        "user": alert.user,
+        "amount": alert.amount,
+        "message": alert.message,
+        "extra": alert.extra,
+        "reward_id": alert.extra.get("reward_id"),
+    })
+}
@@ -0,0 +1,442 @@
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+use twitch_api::{
+    HelixClient,
+    eventsub::channel::chat::{
+        Fragment,
+        message::{Badge, ChannelChatMessageV1Payload},
+    },
+    helix::{
+        bits::{Cheermote, GetCheermotesRequest},
+        chat::{BadgeSet, GetChannelChatBadgesRequest, GetGlobalChatBadgesRequest},
+        users::User,
+    },
+    twitch_oauth2::UserToken,
+    types::UserId,
+};
+
+use crate::metrics::{self, MeteredClient};
+
+const CACHE_TIMEOUT: Duration = Duration::from_secs(30 * 60); // 30 minutes
+/// How long a failed badge or cheermote fetch is cached, so a Helix outage
+/// isn't retried on every chat message.
+const FAILURE_TIMEOUT: Duration = Duration::from_secs(60);
+
+type Cache<K, V> = Mutex<HashMap<K, (V, SystemTime)>>;
+
+/// A chatter's role, also used as the permission needed for commands and
+/// moderation exemptions, see [`Chatter::has_permission`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatRole {
+    #[default]
+    #[serde(alias = "everyone")]
+    Viewer,
+    Subscriber,
+    Vip,
+    Moderator,
+    Broadcaster,
+}
+
+impl ChatRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChatRole::Viewer => "viewer",
+            ChatRole::Subscriber => "subscriber",
+            ChatRole::Vip => "vip",
+            ChatRole::Moderator => "moderator",
+            ChatRole::Broadcaster => "broadcaster",
+        }
+    }
+
+    /// Roles that grant this permission. Subscribers and VIPs are separate,
+    /// neither grants the other.
+    fn granted_by(&self) -> &'static [ChatRole] {
+        match self {
+            ChatRole::Viewer => &[
+                ChatRole::Viewer,
+                ChatRole::Subscriber,
+                ChatRole::Vip,
+                ChatRole::Moderator,
+                ChatRole::Broadcaster,
+            ],
+            ChatRole::Subscriber => &[
+                ChatRole::Subscriber,
+                ChatRole::Moderator,
+                ChatRole::Broadcaster,
+            ],
+            ChatRole::Vip => &[ChatRole::Vip, ChatRole::Moderator, ChatRole::Broadcaster],
+            ChatRole::Moderator => &[ChatRole::Moderator, ChatRole::Broadcaster],
+            ChatRole::Broadcaster => &[ChatRole::Broadcaster],
+        }
+    }
+}
+
+/// Who sent a chat message, derived from their badges.
+#[derive(Clone, Debug)]
+pub struct Chatter {
+    /// The most privileged of `roles`.
+    pub role: ChatRole,
+    /// Every role the badges grant, `Viewer` included.
+    pub roles: Vec<ChatRole>,
+    /// 1, 2 or 3 when subscribed, regardless of role.
+    pub subscriber_tier: Option<u8>,
+    pub subscriber_months: Option<u32>,
+}
+
+impl Chatter {
+    pub fn from_badges(badges: &[Badge]) -> Self {
+        let has = |set: &str| badges.iter().any(|b| b.set_id.as_str() == set);
+        let subscriber = badges
+            .iter()
+            .find(|b| matches!(b.set_id.as_str(), "subscriber" | "founder"));
+        let mut roles = vec![ChatRole::Viewer];
+        if subscriber.is_some() {
+            roles.push(ChatRole::Subscriber);
+        }
+        if has("vip") {
+            roles.push(ChatRole::Vip);
+        }
+        if has("moderator") {
+            roles.push(ChatRole::Moderator);
+        }
+        if has("broadcaster") {
+            roles.push(ChatRole::Broadcaster);
+        }
+        let role = *roles.last().unwrap();
+        // subscriber badge versions are months for tier 1, and 2000/3000 + months for tiers 2/3
+        let subscriber_tier = subscriber.map(|b| match b.id.as_str().parse::<u32>() {
+            Ok(v) if v >= 3000 => 3,
+            Ok(v) if v >= 2000 => 2,
+            _ => 1,
+        });
+        let subscriber_months = subscriber.and_then(|b| b.info.parse().ok());
+        Chatter {
+            role,
+            roles,
+            subscriber_tier,
+            subscriber_months,
+        }
+    }
+
+    /// Whether any of the chatter's roles grants `permission`.
+    pub fn has_permission(&self, permission: ChatRole) -> bool {
+        permission
+            .granted_by()
+            .iter()
+            .any(|role| self.roles.contains(role))
+    }
+}
+
+/// Resolves users, badges, emotes and cheermotes so the page gets a single
+/// ready-to-render `twitch-chat` message.
+pub struct ChatEnricher {
+    client: HelixClient<'static, MeteredClient>,
+    token: Arc<Mutex<UserToken>>,
+    users: Cache<UserId, User>,
+    // keyed by broadcaster, `None` holds the global sets
+    badges: Cache<Option<UserId>, Vec<BadgeSet>>,
+    cheermotes: Cache<UserId, Vec<Cheermote>>,
+}
+
+impl ChatEnricher {
+    pub fn new(client: HelixClient<'static, MeteredClient>, token: Arc<Mutex<UserToken>>) -> Self {
+        ChatEnricher {
+            client,
+            token,
+            users: Mutex::new(HashMap::new()),
+            badges: Mutex::new(HashMap::new()),
+            cheermotes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn enrich(&self, payload: &ChannelChatMessageV1Payload) -> Value {
+        let user = self.user(&payload.chatter_user_id).await;
+        let chatter = Chatter::from_badges(&payload.badges);
+        let badges = self
+            .badges(&payload.broadcaster_user_id, &payload.badges)
+            .await;
+        let fragments = self
+            .fragments(&payload.broadcaster_user_id, &payload.message.fragments)
+            .await;
+        json!({
+            "type": "twitch-chat",
+            "message_id": payload.message_id,
+            "broadcaster_user_id": payload.broadcaster_user_id,
+            "broadcaster_user_login": payload.broadcaster_user_login,
+            "user_id": payload.chatter_user_id,
+            "user_login": payload.chatter_user_login,
+            "user_name": payload.chatter_user_name,
+            "user": user,
+            "color": payload.color,
+            "role": chatter.role.as_str(),
+            "subscriber_tier": chatter.subscriber_tier,
+            "subscriber_months": chatter.subscriber_months,
+            "badges": badges,
+            "text": payload.message.text,
+            "fragments": fragments,
+            "cheer": payload.cheer,
+            "reply": payload.reply,
+            "channel_points_custom_reward_id": payload.channel_points_custom_reward_id,
+        })
+    }
+
+    // the caches are only locked around lookups and inserts, never across a
+    // Helix request, so one slow fetch doesn't hold up every chat message
+    pub async fn user(&self, id: &UserId) -> Option<User> {
+        if let Some((user, cached_at)) = self.users.lock().await.get(id) {
+            if is_fresh(*cached_at) {
+                record_lookup("users", true);
+                return Some(user.clone());
+            }
+            info!("cache expired for user_id: {}", id);
+        } else {
+            info!("fetching user info: {}", id);
+        }
+
+        record_lookup("users", false);
+        let fetched = {
+            let token = self.token.lock().await;
+            self.client.get_user_from_id(id, &*token).await
+        };
+        let mut users = self.users.lock().await;
+        match fetched {
+            Ok(Some(user)) => {
+                users.insert(id.clone(), (user.clone(), SystemTime::now()));
+                Some(user)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                warn!("failed to fetch user {}: {}", id, e);
+                users.get(id).map(|(user, _)| user.clone())
+            }
+        }
+    }
+
+    async fn badges(&self, broadcaster_id: &UserId, badges: &[Badge]) -> Vec<Value> {
+        for key in [None, Some(broadcaster_id.clone())] {
+            let cached = self.badges.lock().await.get(&key).map(|(_, t)| *t);
+            if cached.is_some_and(is_fresh) {
+                record_lookup("badges", true);
+                continue;
+            }
+            record_lookup("badges", false);
+            let sets = {
+                let token = self.token.lock().await;
+                match &key {
+                    None => self
+                        .client
+                        .req_get(GetGlobalChatBadgesRequest::new(), &*token)
+                        .await
+                        .map(|r| r.data),
+                    Some(id) => self
+                        .client
+                        .req_get(GetChannelChatBadgesRequest::broadcaster_id(id), &*token)
+                        .await
+                        .map(|r| r.data),
+                }
+            };
+            let mut cache = self.badges.lock().await;
+            match sets {
+                Ok(sets) => {
+                    cache.insert(key, (sets, SystemTime::now()));
+                }
+                Err(e) => {
+                    warn!("failed to fetch chat badges for {:?}: {}", key, e);
+                    let sets = cache.remove(&key).map(|(sets, _)| sets).unwrap_or_default();
+                    cache.insert(key, (sets, failed_at()));
+                }
+            }
+        }
+
+        let cache = self.badges.lock().await;
+        // channel badges override global ones with the same set and version
+        let lookup = |badge: &Badge| {
+            [Some(broadcaster_id.clone()), None]
+                .iter()
+                .filter_map(|key| cache.get(key))
+                .flat_map(|(sets, _)| sets)
+                .filter(|set| set.set_id == badge.set_id)
+                .flat_map(|set| &set.versions)
+                .find(|version| version.id == badge.id)
+        };
+        badges
+            .iter()
+            .map(|badge| {
+                let image = lookup(badge);
+                json!({
+                    "set_id": badge.set_id,
+                    "id": badge.id,
+                    "info": badge.info,
+                    "title": image.map(|i| i.title.clone()),
+                    "image_url_1x": image.map(|i| i.image_url_1x.clone()),
+                    "image_url_2x": image.map(|i| i.image_url_2x.clone()),
+                    "image_url_4x": image.map(|i| i.image_url_4x.clone()),
+                })
+            })
+            .collect()
+    }
+
+    async fn fragments(&self, broadcaster_id: &UserId, fragments: &[Fragment]) -> Vec<Value> {
+        let has_cheermotes = fragments
+            .iter()
+            .any(|f| matches!(f, Fragment::Cheermote { .. }));
+        let cheermotes = if has_cheermotes {
+            self.cheermotes(broadcaster_id).await
+        } else {
+            Vec::new()
+        };
+
+        fragments
+            .iter()
+            .map(|fragment| {
+                let mut value = serde_json::to_value(fragment).unwrap();
+                match fragment {
+                    Fragment::Emote { emote, .. } => {
+                        let url = || emote.id.url().dark_mode();
+                        value["emote"]["image_url_1x"] = json!(url().size_1x().render());
+                        value["emote"]["image_url_2x"] = json!(url().size_2x().render());
+                        value["emote"]["image_url_3x"] = json!(url().size_3x().render());
+                    }
+                    Fragment::Cheermote { cheermote, .. } => {
+                        let tier = cheermotes
+                            .iter()
+                            .find(|c| c.prefix.eq_ignore_ascii_case(&cheermote.prefix))
+                            .and_then(|c| {
+                                c.tiers
+                                    .iter()
+                                    .filter(|t| t.min_bits <= cheermote.bits as i64)
+                                    .max_by_key(|t| t.min_bits)
+                            });
+                        if let Some(tier) = tier {
+                            let images = &tier.images.dark.animated;
+                            value["cheermote"]["color"] = json!(tier.color);
+                            value["cheermote"]["image_url_1x"] = json!(images.url_1x);
+                            value["cheermote"]["image_url_2x"] = json!(images.url_2x);
+                            value["cheermote"]["image_url_4x"] = json!(images.url_4x);
+                        }
+                    }
+                    _ => {}
+                }
+                value
+            })
+            .collect()
+    }
+
+    async fn cheermotes(&self, broadcaster_id: &UserId) -> Vec<Cheermote> {
+        if let Some((cheermotes, cached_at)) = self.cheermotes.lock().await.get(broadcaster_id)
+            && is_fresh(*cached_at)
+        {
+            record_lookup("cheermotes", true);
+            return cheermotes.clone();
+        }
+        record_lookup("cheermotes", false);
+        let fetched = {
+            let token = self.token.lock().await;
+            let request = GetCheermotesRequest::broadcaster_id(broadcaster_id);
+            self.client.req_get(request, &*token).await
+        };
+        let mut cache = self.cheermotes.lock().await;
+        match fetched {
+            Ok(response) => {
+                cache.insert(
+                    broadcaster_id.clone(),
+                    (response.data.clone(), SystemTime::now()),
+                );
+                response.data
+            }
+            Err(e) => {
+                warn!("failed to fetch cheermotes: {}", e);
+                let cheermotes = cache
+                    .remove(broadcaster_id)
+                    .map(|(c, _)| c)
+                    .unwrap_or_default();
+                cache.insert(broadcaster_id.clone(), (cheermotes.clone(), failed_at()));
+                cheermotes
+            }
+        }
+    }
+}
+
+fn record_lookup(cache: &str, hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    metrics::inc(
+        "webstreamer_cache_requests_total",
+        &[("cache", cache), ("result", result)],
+    );
+}
+
+/// A cache timestamp that expires after `FAILURE_TIMEOUT` instead of
+/// `CACHE_TIMEOUT`, keeping whatever was cached before in the meantime.
+fn failed_at() -> SystemTime {
+    SystemTime::now() - (CACHE_TIMEOUT - FAILURE_TIMEOUT)
+}
+
+fn is_fresh(cached_at: SystemTime) -> bool {
+    SystemTime::now()
+        .duration_since(cached_at)
+        .unwrap_or(CACHE_TIMEOUT)
+        < CACHE_TIMEOUT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chatter(badges: &[(&str, &str)]) -> Chatter {
+        let badges: Vec<Badge> = badges
+            .iter()
+            .map(|(set_id, id)| {
+                serde_json::from_value(json!({ "set_id": set_id, "id": id, "info": "" })).unwrap()
+            })
+            .collect();
+        Chatter::from_badges(&badges)
+    }
+
+    #[test]
+    fn roles_come_from_badges() {
+        let vip_subscriber = chatter(&[("vip", "1"), ("subscriber", "2012")]);
+        assert_eq!(vip_subscriber.role, ChatRole::Vip);
+        assert_eq!(vip_subscriber.subscriber_tier, Some(2));
+        assert_eq!(chatter(&[]).role, ChatRole::Viewer);
+        assert_eq!(chatter(&[("founder", "0")]).role, ChatRole::Subscriber);
+    }
+
+    #[test]
+    fn vip_is_not_a_subscriber() {
+        let vip = chatter(&[("vip", "1")]);
+        assert!(vip.has_permission(ChatRole::Vip));
+        assert!(!vip.has_permission(ChatRole::Subscriber));
+        let subscriber = chatter(&[("subscriber", "3")]);
+        assert!(subscriber.has_permission(ChatRole::Subscriber));
+        assert!(!subscriber.has_permission(ChatRole::Vip));
+        let vip_subscriber = chatter(&[("vip", "1"), ("subscriber", "3")]);
+        assert!(vip_subscriber.has_permission(ChatRole::Subscriber));
+    }
+
+    #[test]
+    fn moderators_have_lower_permissions() {
+        let moderator = chatter(&[("moderator", "1")]);
+        for permission in [ChatRole::Viewer, ChatRole::Subscriber, ChatRole::Vip] {
+            assert!(moderator.has_permission(permission));
+        }
+        assert!(moderator.has_permission(ChatRole::Moderator));
+        assert!(!moderator.has_permission(ChatRole::Broadcaster));
+        assert!(chatter(&[("broadcaster", "1")]).has_permission(ChatRole::Moderator));
+    }
+
+    #[test]
+    fn viewers_only_have_everyone() {
+        let viewer = chatter(&[("glhf-pledge", "1")]);
+        assert!(viewer.has_permission(ChatRole::Viewer));
+        assert!(!viewer.has_permission(ChatRole::Subscriber));
+        assert!(!viewer.has_permission(ChatRole::Vip));
+    }
+}
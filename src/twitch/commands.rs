@@ -0,0 +1,268 @@
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tracing::{debug, info};
+use twitch_api::{eventsub::channel::chat::message::ChannelChatMessageV1Payload, types::UserId};
+
+use super::{
+    chat::{ChatRole, Chatter},
+    metadata::StreamAction,
+    sender::ChatSender,
+};
+use crate::control::Control;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CommandsConfig {
+    pub prefix: String,
+    pub list: Vec<CommandConfig>,
+}
+
+impl Default for CommandsConfig {
+    fn default() -> Self {
+        CommandsConfig {
+            prefix: "!".to_string(),
+            list: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CommandConfig {
+    pub name: String,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    #[serde(default)]
+    pub permission: ChatRole,
+    /// Seconds between uses by anyone.
+    #[serde(default)]
+    pub global_cooldown: u64,
+    /// Seconds between uses by the same chatter.
+    #[serde(default)]
+    pub user_cooldown: u64,
+    #[serde(default)]
+    pub action: CommandAction,
+    /// Threaded reply sent to the chatter, `{user}` is replaced with their name.
+    #[serde(default)]
+    pub reply: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CommandAction {
+    /// Forward to the page as a `chat-command` message.
+    #[default]
+    Page,
+    Reload,
+    /// Show another url, keeping the broadcast up.
+    Navigate(String),
+    SwitchScene(String),
+    /// Stream marker, described by the command's arguments.
+    Marker,
+    Clip,
+}
+
+pub enum Dispatch {
+    Control(Control),
+    Page(Value),
+    Stream(StreamAction),
+}
+
+/// Last uses of commands that are still cooling down.
+#[derive(Default)]
+struct Cooldowns {
+    // (command index, chatter) -> last use, `None` is the global cooldown
+    last_used: HashMap<(usize, Option<UserId>), Instant>,
+}
+
+impl Cooldowns {
+    /// Records a use of `commands[index]` by `user`, unless it is cooling down
+    /// for everyone or for them. Expired uses are dropped on the way, so only
+    /// chatters still cooling down are kept.
+    fn try_use(
+        &mut self,
+        commands: &[CommandConfig],
+        index: usize,
+        user: &UserId,
+        now: Instant,
+    ) -> bool {
+        self.last_used.retain(|(index, user), used| {
+            let command = &commands[*index];
+            let cooldown = match user {
+                Some(_) => command.user_cooldown,
+                None => command.global_cooldown,
+            };
+            now.duration_since(*used) < Duration::from_secs(cooldown)
+        });
+        let user_key = (index, Some(user.clone()));
+        if self.last_used.contains_key(&(index, None)) || self.last_used.contains_key(&user_key) {
+            return false;
+        }
+        let command = &commands[index];
+        if command.global_cooldown > 0 {
+            self.last_used.insert((index, None), now);
+        }
+        if command.user_cooldown > 0 {
+            self.last_used.insert(user_key, now);
+        }
+        true
+    }
+}
+
+/// Parses `!command args` chat messages and applies permissions and cooldowns.
+pub struct CommandRouter {
+    config: CommandsConfig,
+    sender: ChatSender,
+    cooldowns: Mutex<Cooldowns>,
+}
+
+impl CommandRouter {
+    pub fn new(config: CommandsConfig, sender: ChatSender) -> Self {
+        CommandRouter {
+            config,
+            sender,
+            cooldowns: Mutex::new(Cooldowns::default()),
+        }
+    }
+
+    pub async fn route(&self, payload: &ChannelChatMessageV1Payload) -> Option<Dispatch> {
+        let text = payload
+            .message
+            .text
+            .trim()
+            .strip_prefix(&self.config.prefix)?;
+        let mut words = text.split_whitespace();
+        let invoked = words.next()?.to_lowercase();
+        let args: Vec<&str> = words.collect();
+
+        let (index, command) = self.config.list.iter().enumerate().find(|(_, c)| {
+            c.name.eq_ignore_ascii_case(&invoked)
+                || c.aliases.iter().any(|a| a.eq_ignore_ascii_case(&invoked))
+        })?;
+
+        let chatter = Chatter::from_badges(&payload.badges);
+        if !chatter.has_permission(command.permission) {
+            debug!(
+                "{} lacks permission for command {}",
+                payload.chatter_user_login, command.name
+            );
+            return None;
+        }
+
+        let allowed = self.cooldowns.lock().await.try_use(
+            &self.config.list,
+            index,
+            &payload.chatter_user_id,
+            Instant::now(),
+        );
+        if !allowed {
+            debug!(
+                "command {} on cooldown for {}",
+                command.name, payload.chatter_user_login
+            );
+            return None;
+        }
+
+        info!(
+            "command {} from {}: {:?}",
+            command.name, payload.chatter_user_login, args
+        );
+        if let Some(reply) = &command.reply {
+            let reply = reply.replace("{user}", payload.chatter_user_name.as_str());
+            self.sender
+                .reply(&payload.broadcaster_user_id, &payload.message_id, &reply);
+        }
+        Some(match &command.action {
+            CommandAction::Page => Dispatch::Page(json!({
+                "type": "chat-command",
+                "command": command.name,
+                "invoked_as": invoked,
+                "args": args,
+                "message_id": payload.message_id,
+                "broadcaster_user_id": payload.broadcaster_user_id,
+                "user_id": payload.chatter_user_id,
+                "user_login": payload.chatter_user_login,
+                "user_name": payload.chatter_user_name,
+                "role": chatter.role.as_str(),
+            })),
+            CommandAction::Reload => Dispatch::Control(Control::Reload),
+            CommandAction::Navigate(url) => Dispatch::Control(Control::Navigate(url.clone())),
+            CommandAction::SwitchScene(scene) => {
+                Dispatch::Control(Control::SwitchScene(scene.clone()))
+            }
+            CommandAction::Marker => {
+                let description = Some(args.join(" ")).filter(|d| !d.is_empty());
+                Dispatch::Stream(StreamAction::Marker(description))
+            }
+            CommandAction::Clip => Dispatch::Stream(StreamAction::Clip),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(global_cooldown: u64, user_cooldown: u64) -> Vec<CommandConfig> {
+        vec![
+            serde_json::from_value(json!({
+                "name": "hello",
+                "global_cooldown": global_cooldown,
+                "user_cooldown": user_cooldown,
+            }))
+            .unwrap(),
+        ]
+    }
+
+    #[test]
+    fn global_cooldown_applies_to_everyone() {
+        let commands = commands(10, 0);
+        let mut cooldowns = Cooldowns::default();
+        let now = Instant::now();
+        assert!(cooldowns.try_use(&commands, 0, &"1".into(), now));
+        assert!(!cooldowns.try_use(&commands, 0, &"2".into(), now + Duration::from_secs(5)));
+        assert!(cooldowns.try_use(&commands, 0, &"2".into(), now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn user_cooldown_applies_per_chatter() {
+        let commands = commands(0, 10);
+        let mut cooldowns = Cooldowns::default();
+        let now = Instant::now();
+        assert!(cooldowns.try_use(&commands, 0, &"1".into(), now));
+        assert!(cooldowns.try_use(&commands, 0, &"2".into(), now));
+        assert!(!cooldowns.try_use(&commands, 0, &"1".into(), now + Duration::from_secs(9)));
+        assert!(cooldowns.try_use(&commands, 0, &"1".into(), now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn expired_cooldowns_are_pruned() {
+        let commands = commands(5, 10);
+        let mut cooldowns = Cooldowns::default();
+        let now = Instant::now();
+        for user in ["1", "2", "3"] {
+            cooldowns.try_use(&commands, 0, &user.into(), now);
+        }
+        // the global use and chatter 1, the others were on cooldown
+        assert_eq!(cooldowns.last_used.len(), 2);
+        assert!(cooldowns.try_use(&commands, 0, &"4".into(), now + Duration::from_secs(6)));
+        assert_eq!(cooldowns.last_used.len(), 3);
+        assert!(cooldowns.try_use(&commands, 0, &"5".into(), now + Duration::from_secs(20)));
+        // only the new global use and chatter 5
+        assert_eq!(cooldowns.last_used.len(), 2);
+    }
+
+    #[test]
+    fn no_cooldown_keeps_nothing() {
+        let commands = commands(0, 0);
+        let mut cooldowns = Cooldowns::default();
+        let now = Instant::now();
+        assert!(cooldowns.try_use(&commands, 0, &"1".into(), now));
+        assert!(cooldowns.try_use(&commands, 0, &"1".into(), now));
+        assert!(cooldowns.last_used.is_empty());
+    }
+}
@@ -1,11 +1,17 @@
 // Adapted from https://github.com/twitch-rs/twitch_api/blob/main/examples/chatbot/src/websocket.rs
 use futures::StreamExt;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::{fmt, sync::Arc, time::Duration};
+use tokio::{sync::Mutex, time::sleep};
 use tokio_tungstenite::tungstenite;
 use tracing::{info, warn};
+
+use super::ordering::Notification;
+use crate::{
+    metrics::{self, MeteredClient},
+    status::{SessionStatus, Status},
+};
 use twitch_api::{
-    HelixClient,
+    HelixClient, TWITCH_EVENTSUB_WEBSOCKET_URL,
     eventsub::{
         self, Event,
         event::websocket::{EventsubWebsocketData, ReconnectPayload, SessionData, WelcomePayload},
@@ -14,11 +20,63 @@ use twitch_api::{
     types::{self},
 };
 
+/// Twitch allows 300 enabled subscriptions per websocket session and 3
+/// sessions per user token.
+pub const CHANNELS_PER_SESSION: usize = (300 - ALERT_SUBSCRIPTIONS) / SUBSCRIPTIONS_PER_CHANNEL;
+pub const MAX_SESSIONS: usize = 3;
+const SUBSCRIPTIONS_PER_CHANNEL: usize = 6;
+/// Follows, subs, gifts, resubs, cheers and redemptions, plus ad breaks and
+/// the two poll subscriptions.
+const ALERT_SUBSCRIPTIONS: usize = 9;
+/// Twitch allows a total cost of 10 across all websocket sessions of a user
+/// token, so more sessions don't help. Subscriptions to the token owner's
+/// channel and chat subscriptions (authorized by the owner as `user_id`) are
+/// free, the stream online and offline, channel update and raid subscriptions
+/// of other channels cost 1 each.
+const MAX_TOTAL_COST: usize = 10;
+const COST_PER_CHANNEL: usize = 4;
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Why a session's connection ended. Only that session reconnects, the
+/// others keep running.
+enum SessionError {
+    Connect(tungstenite::Error),
+    Connection(tungstenite::Error),
+    /// Twitch revoked a subscription, resubscribing happens on the new
+    /// connection's welcome.
+    Revoked(String),
+    Closed,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::Connect(e) => write!(f, "failed to connect: {}", e),
+            SessionError::Connection(e) => write!(f, "connection failed: {}", e),
+            SessionError::Revoked(revocation) => write!(f, "subscription revoked: {}", revocation),
+            SessionError::Closed => write!(f, "connection closed"),
+        }
+    }
+}
+
 pub struct EventWebsocketClient {
+    /// Position among the sessions, for status reporting.
+    pub index: usize,
+    pub status: Status,
     pub session_id: Option<String>,
     pub token: Arc<Mutex<UserToken>>,
-    pub client: HelixClient<'static, reqwest::Client>,
+    pub client: HelixClient<'static, MeteredClient>,
     pub chats: Vec<twitch_api::types::UserId>,
+    /// Channels among `chats` that also get stream, channel update and raid
+    /// events, see [`budget_channels`].
+    pub stream_events: Vec<twitch_api::types::UserId>,
+    /// Own channel to subscribe to follows, subs, cheers and redemptions for.
+    pub alert_channel: Option<twitch_api::types::UserId>,
+    /// Also subscribe to ad breaks on the alert channel.
+    pub ad_breaks: bool,
+    /// Also subscribe to native poll votes on the alert channel.
+    pub polls: bool,
     pub connect_url: String,
 }
 
@@ -26,129 +84,369 @@ impl EventWebsocketClient {
     /// Connect to the websocket and return the stream
     async fn connect(
         &self,
-    ) -> tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>
-    {
+    ) -> Result<
+        tokio_tungstenite::WebSocketStream<
+            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+        >,
+        tungstenite::Error,
+    > {
         tracing::info!("connecting to twitch");
         let config = tungstenite::protocol::WebSocketConfig::default();
         let (socket, _) =
             tokio_tungstenite::connect_async_with_config(&self.connect_url, Some(config), false)
-                .await
-                .unwrap();
-
-        socket
+                .await?;
+        Ok(socket)
     }
 
-    pub async fn run<Fut>(mut self, mut event_fn: impl FnMut(Event, types::Timestamp) -> Fut)
+    /// Keeps the session connected, reconnecting with a growing delay
+    /// whenever its connection ends.
+    pub async fn run<Fut>(mut self, mut event_fn: impl FnMut(Notification) -> Fut)
     where
         Fut: std::future::Future<Output = ()>,
     {
+        let session = self.index.to_string();
+        let mut delay = MIN_RECONNECT_DELAY;
         loop {
-            info!("connecting to twitch");
-            let mut s = self.connect().await;
-            while let Some(msg) = futures::StreamExt::next(&mut s).await {
-                let msg = match msg {
-                    Err(tungstenite::Error::Protocol(
-                        tungstenite::error::ProtocolError::ResetWithoutClosingHandshake,
-                    )) => {
-                        warn!(
-                            "connection was sent an unexpected frame or was reset, reestablishing it"
-                        );
-                        continue;
-                    }
-                    _ => msg.unwrap(),
-                };
-                self.process_message(msg, &mut event_fn).await;
+            let error = self.run_connection(&mut event_fn).await;
+            warn!("eventsub session {} disconnected: {}", self.index, error);
+            self.status.update(|s| {
+                if let Some(session) = s.eventsub.get_mut(&self.index) {
+                    session.connected = false;
+                }
+            });
+            metrics::inc(
+                "webstreamer_eventsub_reconnects_total",
+                &[("session", &session)],
+            );
+            if let SessionError::Connect(_) = error {
+                // a reconnect url only works once, start a new session instead
+                self.connect_url = TWITCH_EVENTSUB_WEBSOCKET_URL.to_string();
+            }
+            if self.session_id.take().is_some() {
+                // it was up, so this isn't a failing reconnect
+                delay = MIN_RECONNECT_DELAY;
             }
+            sleep(delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
         }
     }
 
+    /// Runs one connection until it ends.
+    async fn run_connection<Fut>(
+        &mut self,
+        event_fn: &mut impl FnMut(Notification) -> Fut,
+    ) -> SessionError
+    where
+        Fut: std::future::Future<Output = ()>,
+    {
+        let mut s = match self.connect().await {
+            Ok(s) => s,
+            Err(e) => return SessionError::Connect(e),
+        };
+        while let Some(msg) = futures::StreamExt::next(&mut s).await {
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(e) => return SessionError::Connection(e),
+            };
+            if let Err(e) = self.process_message(msg, event_fn).await {
+                return e;
+            }
+        }
+        SessionError::Closed
+    }
+
     async fn process_message<Fut>(
         &mut self,
         msg: tungstenite::Message,
-        event_fn: &mut impl FnMut(Event, types::Timestamp) -> Fut,
-    ) where
+        event_fn: &mut impl FnMut(Notification) -> Fut,
+    ) -> Result<(), SessionError>
+    where
         Fut: std::future::Future<Output = ()>,
     {
         match msg {
-            tungstenite::Message::Text(s) => match Event::parse_websocket(&s).unwrap() {
-                EventsubWebsocketData::Welcome {
-                    payload: WelcomePayload { session },
-                    ..
-                }
-                | EventsubWebsocketData::Reconnect {
-                    payload: ReconnectPayload { session },
-                    ..
-                } => {
-                    self.process_welcome_message(session).await;
-                }
-                EventsubWebsocketData::Notification { metadata, payload } => {
-                    event_fn(payload, metadata.message_timestamp.into_owned()).await;
-                }
-                re @ EventsubWebsocketData::Revocation { .. } => {
-                    panic!("got revocation event: {re:?}")
+            tungstenite::Message::Text(s) => {
+                let data = match Event::parse_websocket(&s) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        warn!("failed to parse eventsub message: {}", e);
+                        return Ok(());
+                    }
+                };
+                let message_type = match &data {
+                    EventsubWebsocketData::Welcome { .. } => "session_welcome".to_string(),
+                    EventsubWebsocketData::Reconnect { .. } => "session_reconnect".to_string(),
+                    EventsubWebsocketData::Keepalive { .. } => "session_keepalive".to_string(),
+                    EventsubWebsocketData::Revocation { .. } => "revocation".to_string(),
+                    EventsubWebsocketData::Notification { metadata, .. } => {
+                        metadata.subscription_type.to_string()
+                    }
+                    _ => "unknown".to_string(),
+                };
+                metrics::inc(
+                    "webstreamer_eventsub_messages_total",
+                    &[("type", &message_type)],
+                );
+                match data {
+                    EventsubWebsocketData::Welcome {
+                        payload: WelcomePayload { session },
+                        ..
+                    }
+                    | EventsubWebsocketData::Reconnect {
+                        payload: ReconnectPayload { session },
+                        ..
+                    } => {
+                        self.process_welcome_message(session).await;
+                    }
+                    EventsubWebsocketData::Notification { metadata, payload } => {
+                        event_fn(Notification {
+                            message_id: metadata.message_id.into_owned(),
+                            subscription_type: metadata.subscription_type.to_string(),
+                            event: payload,
+                            timestamp: metadata.message_timestamp.into_owned(),
+                        })
+                        .await;
+                    }
+                    re @ EventsubWebsocketData::Revocation { .. } => {
+                        return Err(SessionError::Revoked(format!("{:?}", re)));
+                    }
+                    EventsubWebsocketData::Keepalive {
+                        metadata: _,
+                        payload: _,
+                    } => (),
+                    _ => (),
                 }
-                EventsubWebsocketData::Keepalive {
-                    metadata: _,
-                    payload: _,
-                } => (),
-                _ => (),
-            },
-            tungstenite::Message::Close(_) => {
-                warn!("websocket connection closed, attempting to reconnect");
-                return;
             }
+            tungstenite::Message::Close(_) => return Err(SessionError::Closed),
             _ => (),
         }
+        Ok(())
     }
 
     async fn process_welcome_message(&mut self, data: SessionData<'_>) {
         info!("connected to twitch chat");
         self.session_id = Some(data.id.to_string());
+        let session = SessionStatus {
+            connected: true,
+            session_id: self.session_id.clone(),
+            channels: self.chats.len(),
+            chat_only: self
+                .chats
+                .iter()
+                .filter(|id| !self.stream_events.contains(id))
+                .map(ToString::to_string)
+                .collect(),
+        };
+        self.status.update(|s| {
+            s.eventsub.insert(self.index, session);
+        });
         if let Some(url) = data.reconnect_url {
             self.connect_url = url.parse().unwrap();
         }
         let token = self.token.lock().await;
         let transport = eventsub::Transport::websocket(data.id.clone());
-        for id in &self.chats {
-            let user_id = token.user_id().unwrap().to_owned();
-
-            let mut subs = Vec::new();
-            let subscription_stream = self.client.get_eventsub_subscriptions(
-                Some(eventsub::Status::Enabled),
-                None,
-                None,
-                &*token,
-            );
+        let user_id = token.user_id().unwrap().to_owned();
 
-            futures::pin_mut!(subscription_stream);
-            while let Some(result) = subscription_stream.next().await {
-                if let Ok(response) = result {
-                    subs.extend(response.subscriptions.into_iter().filter(|s| {
-                        s.transport
-                            .as_websocket()
-                            .is_some_and(|t| t.session_id == data.id)
-                    }));
-                }
+        let mut subs = Vec::new();
+        let subscription_stream = self.client.get_eventsub_subscriptions(
+            Some(eventsub::Status::Enabled),
+            None,
+            None,
+            &*token,
+        );
+        futures::pin_mut!(subscription_stream);
+        while let Some(result) = subscription_stream.next().await {
+            if let Ok(response) = result {
+                subs.extend(response.subscriptions.into_iter().filter(|s| {
+                    s.transport
+                        .as_websocket()
+                        .is_some_and(|t| t.session_id == data.id)
+                }));
             }
+        }
 
-            if !subs.is_empty() {
+        for id in &self.chats {
+            if subs
+                .iter()
+                .any(|s| subscription_channel(&s.condition) == Some(id.clone()))
+            {
+                continue;
+            }
+            info!("subscribing to events for channel {}", id);
+            self.subscribe(
+                eventsub::channel::chat::ChannelChatMessageV1::new(id.clone(), user_id.clone()),
+                &transport,
+                &token,
+            )
+            .await;
+            self.subscribe(
+                eventsub::channel::chat::ChannelChatNotificationV1::new(
+                    id.clone(),
+                    user_id.clone(),
+                ),
+                &transport,
+                &token,
+            )
+            .await;
+            if !self.stream_events.contains(id) {
                 continue;
             }
+            self.subscribe(
+                eventsub::stream::StreamOnlineV1::broadcaster_user_id(id.clone()),
+                &transport,
+                &token,
+            )
+            .await;
+            self.subscribe(
+                eventsub::stream::StreamOfflineV1::broadcaster_user_id(id.clone()),
+                &transport,
+                &token,
+            )
+            .await;
+            self.subscribe(
+                eventsub::channel::ChannelUpdateV2::broadcaster_user_id(id.clone()),
+                &transport,
+                &token,
+            )
+            .await;
+            self.subscribe(
+                eventsub::channel::ChannelRaidV1::to_broadcaster_user_id(id.clone()),
+                &transport,
+                &token,
+            )
+            .await;
+        }
 
-            let message =
-                eventsub::channel::chat::ChannelChatMessageV1::new(id.clone(), user_id.clone());
-            self.client
-                .create_eventsub_subscription(message, transport.clone(), &*token)
-                .await
-                .unwrap();
-            self.client
-                .create_eventsub_subscription(
-                    eventsub::channel::chat::ChannelChatNotificationV1::new(id.clone(), user_id),
-                    transport.clone(),
-                    &*token,
-                )
-                .await
-                .unwrap();
+        let Some(id) = &self.alert_channel else {
+            return;
+        };
+        if subs
+            .iter()
+            .any(|s| s.type_ == eventsub::EventType::ChannelFollow)
+        {
+            return;
+        }
+        info!("subscribing to alerts for channel {}", id);
+        self.subscribe(
+            eventsub::channel::ChannelFollowV2::new(id.clone(), user_id.clone()),
+            &transport,
+            &token,
+        )
+        .await;
+        self.subscribe(
+            eventsub::channel::ChannelSubscribeV1::broadcaster_user_id(id.clone()),
+            &transport,
+            &token,
+        )
+        .await;
+        self.subscribe(
+            eventsub::channel::ChannelSubscriptionGiftV1::broadcaster_user_id(id.clone()),
+            &transport,
+            &token,
+        )
+        .await;
+        self.subscribe(
+            eventsub::channel::ChannelSubscriptionMessageV1::broadcaster_user_id(id.clone()),
+            &transport,
+            &token,
+        )
+        .await;
+        self.subscribe(
+            eventsub::channel::ChannelCheerV1::broadcaster_user_id(id.clone()),
+            &transport,
+            &token,
+        )
+        .await;
+        self.subscribe(
+            eventsub::channel::ChannelPointsCustomRewardRedemptionAddV1::broadcaster_user_id(
+                id.clone(),
+            ),
+            &transport,
+            &token,
+        )
+        .await;
+        if self.ad_breaks {
+            self.subscribe(
+                eventsub::channel::ChannelAdBreakBeginV1::broadcaster_user_id(id.clone()),
+                &transport,
+                &token,
+            )
+            .await;
         }
+        if self.polls {
+            self.subscribe(
+                eventsub::channel::ChannelPollProgressV1::broadcaster_user_id(id.clone()),
+                &transport,
+                &token,
+            )
+            .await;
+            self.subscribe(
+                eventsub::channel::ChannelPollEndV1::broadcaster_user_id(id.clone()),
+                &transport,
+                &token,
+            )
+            .await;
+        }
+    }
+
+    async fn subscribe<E: eventsub::EventSubscription + Send>(
+        &self,
+        event: E,
+        transport: &eventsub::Transport,
+        token: &UserToken,
+    ) {
+        let event_type = E::EVENT_TYPE;
+        if let Err(e) = self
+            .client
+            .create_eventsub_subscription(event, transport.clone(), token)
+            .await
+        {
+            warn!("failed to subscribe to {:?}: {}", event_type, e);
+        }
+    }
+}
+
+/// Splits `channels` into the ones that get all their subscriptions within
+/// the cost limit and the ones that only get chat, keeping their order.
+/// `owner`'s subscriptions are free.
+pub fn budget_channels(
+    channels: &[types::UserId],
+    owner: &types::UserId,
+) -> (Vec<types::UserId>, Vec<types::UserId>) {
+    let mut cost = 0;
+    channels.iter().cloned().partition(|id| {
+        if id == owner {
+            return true;
+        }
+        if cost + COST_PER_CHANNEL > MAX_TOTAL_COST {
+            return false;
+        }
+        cost += COST_PER_CHANNEL;
+        true
+    })
+}
+
+/// The channel an event belongs to, from its subscription condition.
+pub fn event_channel(event: &Event) -> Option<types::UserId> {
+    subscription_channel(&event.subscription().ok()?.condition)
+}
+
+fn subscription_channel(condition: &serde_json::Value) -> Option<types::UserId> {
+    condition["broadcaster_user_id"]
+        .as_str()
+        .or(condition["to_broadcaster_user_id"].as_str())
+        .filter(|id| !id.is_empty())
+        .map(types::UserId::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budgets_other_channels_by_cost() {
+        let ids: Vec<types::UserId> = ["a", "owner", "b", "c", "d"].map(Into::into).to_vec();
+        let (full, chat_only) = budget_channels(&ids, &"owner".into());
+        assert_eq!(full, ["a", "owner", "b"].map(types::UserId::from));
+        assert_eq!(chat_only, ["c", "d"].map(types::UserId::from));
     }
 }
@@ -0,0 +1,233 @@
+use reqwest::{Method, header};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+use twitch_api::{
+    HelixClient, HttpClient,
+    client::Request,
+    helix::{
+        channels::{
+            ContentClassificationLabel, ModifyChannelInformationBody,
+            ModifyChannelInformationRequest,
+        },
+        games::GetGamesRequest,
+        streams::{CreateStreamMarkerBody, CreateStreamMarkerRequest},
+    },
+    twitch_oauth2::{TwitchToken, UserToken},
+    types::{CategoryId, ContentClassificationId},
+};
+
+use crate::metrics::MeteredClient;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MetadataConfig {
+    /// Set when the stream goes online and on scene switches, `{scene}` is
+    /// replaced with the current scene.
+    pub title: Option<String>,
+    /// Category (game) name, e.g. `Just Chatting`.
+    pub category: Option<String>,
+    pub tags: Option<Vec<String>>,
+    /// Content classification label ids (`MatureGame`, `ProfanityVulgarity`,
+    /// ...) to turn on or off.
+    pub content_labels: HashMap<String, bool>,
+}
+
+/// Markers and clips, requested by the page or chat commands.
+#[derive(Debug, Clone)]
+pub enum StreamAction {
+    Marker(Option<String>),
+    Clip,
+}
+
+/// Keeps the channel title, category, tags and content labels in line with
+/// the config, and creates stream markers and clips.
+pub struct Metadata {
+    config: MetadataConfig,
+    client: HelixClient<'static, MeteredClient>,
+    token: Arc<Mutex<UserToken>>,
+    scene: Mutex<Option<String>>,
+}
+
+impl Metadata {
+    pub fn new(
+        config: MetadataConfig,
+        client: HelixClient<'static, MeteredClient>,
+        token: Arc<Mutex<UserToken>>,
+    ) -> Self {
+        Metadata {
+            config,
+            client,
+            token,
+            scene: Mutex::new(None),
+        }
+    }
+
+    /// Updates the channel information from the config.
+    pub async fn apply(&self) {
+        let config = &self.config;
+        if config.title.is_none()
+            && config.category.is_none()
+            && config.tags.is_none()
+            && config.content_labels.is_empty()
+        {
+            return;
+        }
+        let scene = self.scene.lock().await.clone().unwrap_or_default();
+        let title = config.title.as_ref().map(|t| t.replace("{scene}", &scene));
+        let token = self.token.lock().await;
+        let category = match &config.category {
+            Some(name) => self.category_id(name, &token).await,
+            None => None,
+        };
+        let tags: Option<Vec<&str>> = config
+            .tags
+            .as_ref()
+            .map(|tags| tags.iter().map(String::as_str).collect());
+        let labels: Vec<ContentClassificationLabel> = config
+            .content_labels
+            .iter()
+            .filter_map(|(id, enabled)| {
+                match serde_json::from_value::<ContentClassificationId>(json!(id)) {
+                    Ok(id) => Some(ContentClassificationLabel::new(*enabled, id)),
+                    Err(e) => {
+                        warn!("invalid content label {}: {}", id, e);
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        let mut body = ModifyChannelInformationBody::new();
+        if let Some(title) = &title {
+            body.title(title.as_str());
+        }
+        if let Some(category) = &category {
+            body.game_id(category);
+        }
+        if let Some(tags) = &tags {
+            body.tags(tags);
+        }
+        if !labels.is_empty() {
+            body.content_classification_labels(labels);
+        }
+        info!(
+            "updating channel information, title: {:?}, category: {:?}",
+            title, config.category
+        );
+        let request = ModifyChannelInformationRequest::broadcaster_id(&token.user_id);
+        if let Err(e) = self.client.req_patch(request, body, &*token).await {
+            warn!("failed to update channel information: {}", e);
+        }
+    }
+
+    /// Remembers the scene for `{scene}`, and updates the title if it uses it.
+    pub async fn set_scene(&self, scene: &str) {
+        *self.scene.lock().await = Some(scene.to_string());
+        if self
+            .config
+            .title
+            .as_ref()
+            .is_some_and(|t| t.contains("{scene}"))
+        {
+            self.apply().await;
+        }
+    }
+
+    /// Runs the action, returning the message to send to the page.
+    pub async fn run(&self, action: StreamAction) -> Option<Value> {
+        match action {
+            StreamAction::Marker(description) => self.marker(description).await,
+            StreamAction::Clip => self.clip().await,
+        }
+    }
+
+    async fn marker(&self, description: Option<String>) -> Option<Value> {
+        let token = self.token.lock().await;
+        let body = match &description {
+            Some(description) => CreateStreamMarkerBody::new(&token.user_id, description.as_str()),
+            None => CreateStreamMarkerBody::user_id(&token.user_id),
+        };
+        match self
+            .client
+            .req_post(CreateStreamMarkerRequest::new(), body, &*token)
+            .await
+        {
+            Ok(response) => {
+                let marker = response.data;
+                info!("created stream marker at {}s", marker.position_seconds);
+                Some(json!({
+                    "type": "stream-marker",
+                    "id": marker.id,
+                    "position_seconds": marker.position_seconds,
+                    "description": marker.description,
+                }))
+            }
+            Err(e) => {
+                // fails while offline
+                warn!("failed to create stream marker: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn clip(&self) -> Option<Value> {
+        let token = self.token.lock().await;
+        // twitch_api sends create clip as a GET, twitch wants a POST
+        let url = format!(
+            "https://api.twitch.tv/helix/clips?broadcaster_id={}",
+            token.user_id
+        );
+        let mut request = Request::new(Default::default());
+        *request.method_mut() = Method::POST;
+        *request.uri_mut() = url.parse().unwrap();
+        let headers = request.headers_mut();
+        headers.insert("Client-Id", token.client_id().as_str().parse().unwrap());
+        let authorization = format!("Bearer {}", token.token().secret());
+        headers.insert(header::AUTHORIZATION, authorization.parse().unwrap());
+        let response = self.client.get_client().req(request).await;
+        let body = match response {
+            Ok(response) => String::from_utf8_lossy(response.body()).into_owned(),
+            Err(e) => {
+                warn!("failed to create clip: {}", e);
+                return None;
+            }
+        };
+        let clip = serde_json::from_str::<Value>(&body)
+            .ok()
+            .and_then(|body| body["data"].get(0).cloned());
+        match clip {
+            Some(clip) => {
+                info!("created clip {}", clip["id"]);
+                Some(json!({ "type": "clip", "id": clip["id"], "edit_url": clip["edit_url"] }))
+            }
+            None => {
+                warn!("failed to create clip: {}", body);
+                None
+            }
+        }
+    }
+
+    async fn category_id(&self, name: &str, token: &UserToken) -> Option<CategoryId> {
+        let names = vec![name.to_string()];
+        match self
+            .client
+            .req_get(GetGamesRequest::names(names), token)
+            .await
+        {
+            Ok(response) => match response.data.into_iter().next() {
+                Some(game) => Some(game.id),
+                None => {
+                    warn!("unknown category: {}", name);
+                    None
+                }
+            },
+            Err(e) => {
+                warn!("failed to look up category {}: {}", name, e);
+                None
+            }
+        }
+    }
+}
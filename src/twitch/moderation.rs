@@ -0,0 +1,475 @@
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{spawn, sync::Mutex};
+use tracing::{info, warn};
+use twitch_api::{
+    HelixClient,
+    eventsub::{
+        Event, Message, Payload,
+        channel::chat::{Fragment, message::ChannelChatMessageV1Payload},
+    },
+    twitch_oauth2::{TwitchToken, UserToken},
+    types::UserId,
+};
+
+use super::chat::{ChatRole, Chatter};
+use crate::metrics::MeteredClient;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ModerationConfig {
+    /// Chatters with this permission skip all rules.
+    pub exempt: ChatRole,
+    pub rules: Vec<Rule>,
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        ModerationConfig {
+            exempt: ChatRole::Moderator,
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl ModerationConfig {
+    /// Whether any rule acts on Twitch itself, which needs moderator scopes.
+    pub fn moderates_twitch(&self) -> bool {
+        self.rules.iter().any(|r| r.action != Action::Drop)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Rule {
+    pub filter: Filter,
+    #[serde(default)]
+    pub action: Action,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Filter {
+    /// Case-insensitive substrings.
+    BlockedTerms(Vec<String>),
+    /// Any link, unless the chatter has the given permission.
+    Links {
+        #[serde(default = "links_default_permission")]
+        permission: ChatRole,
+    },
+    /// Share of uppercase letters in messages with at least `min_length` letters.
+    Caps { ratio: f32, min_length: usize },
+    /// More than this many emotes in one message.
+    Emotes(usize),
+    /// More than `messages` from one chatter within `seconds`.
+    Rate { messages: usize, seconds: u64 },
+}
+
+fn links_default_permission() -> ChatRole {
+    ChatRole::Moderator
+}
+
+/// Ordered by severity, every action also hides the message from the page.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    #[default]
+    Drop,
+    Delete,
+    /// Seconds.
+    Timeout(u32),
+}
+
+/// Top-level domains that make `word.tld` count as a link without a scheme or
+/// `www.`, so ordinary words joined by a dot don't.
+const LINK_TLDS: &[&str] = &[
+    "ai", "app", "be", "biz", "ca", "cc", "club", "co", "com", "de", "dev", "eu", "fm", "fr", "gg",
+    "info", "io", "jp", "link", "live", "ly", "me", "net", "online", "org", "ru", "shop", "site",
+    "store", "to", "top", "tv", "uk", "us", "xyz",
+];
+
+/// Runs chat messages through the configured rules before they are forwarded,
+/// and deletes messages or times out chatters on Twitch when rules say so.
+pub struct Moderation {
+    config: ModerationConfig,
+    client: HelixClient<'static, MeteredClient>,
+    token: Arc<Mutex<UserToken>>,
+    recent: Mutex<HashMap<UserId, VecDeque<Instant>>>,
+}
+
+impl Moderation {
+    pub fn new(
+        config: ModerationConfig,
+        client: HelixClient<'static, MeteredClient>,
+        token: Arc<Mutex<UserToken>>,
+    ) -> Self {
+        Moderation {
+            config,
+            client,
+            token,
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns whether the message may be shown on the page.
+    pub async fn allow(&self, payload: &ChannelChatMessageV1Payload) -> bool {
+        let chatter = Chatter::from_badges(&payload.badges);
+        if chatter.has_permission(self.config.exempt) {
+            return true;
+        }
+        let rate = self.record(&payload.chatter_user_id).await;
+
+        let Some((rule, action)) = self
+            .config
+            .rules
+            .iter()
+            .filter(|rule| match &rule.filter {
+                Filter::BlockedTerms(terms) => contains_term(&payload.message.text, terms),
+                Filter::Links { permission } => {
+                    !chatter.has_permission(*permission) && contains_link(&payload.message.text)
+                }
+                Filter::Caps { ratio, min_length } => {
+                    caps_ratio(&payload.message.fragments, *min_length) > *ratio
+                }
+                Filter::Emotes(max) => {
+                    let emotes = payload
+                        .message
+                        .fragments
+                        .iter()
+                        .filter(|f| matches!(f, Fragment::Emote { .. }))
+                        .count();
+                    emotes > *max
+                }
+                Filter::Rate { messages, seconds } => {
+                    rate.iter()
+                        .filter(|t| t.elapsed() < Duration::from_secs(*seconds))
+                        .count()
+                        > *messages
+                }
+            })
+            .map(|rule| (rule, &rule.action))
+            .max_by_key(|(_, action)| *action)
+        else {
+            return true;
+        };
+
+        info!(
+            "moderation: {:?} message {} from {}, filter: {:?}",
+            action, payload.message_id, payload.chatter_user_login, rule.filter
+        );
+        if *action != Action::Drop {
+            // on its own task, so Twitch being slow doesn't hold up the events after this one
+            let client = self.client.clone();
+            let token = self.token.clone();
+            let action = action.clone();
+            let payload = payload.clone();
+            spawn(async move {
+                let token = token.lock().await;
+                let moderator_id = token.user_id().unwrap().to_owned();
+                if payload.broadcaster_user_id != moderator_id {
+                    // only the overlay can be moderated in other channels
+                    return;
+                }
+                let result = match action {
+                    Action::Drop => Ok(()),
+                    Action::Delete => client
+                        .delete_chat_message(
+                            &payload.broadcaster_user_id,
+                            &moderator_id,
+                            &payload.message_id,
+                            &*token,
+                        )
+                        .await
+                        .map(|_| ()),
+                    Action::Timeout(seconds) => client
+                        .ban_user(
+                            &payload.chatter_user_id,
+                            "automatic chat filter",
+                            seconds,
+                            &payload.broadcaster_user_id,
+                            &moderator_id,
+                            &*token,
+                        )
+                        .await
+                        .map(|_| ()),
+                };
+                if let Err(e) = result {
+                    warn!("moderation action {:?} failed: {}", action, e);
+                }
+            });
+        }
+        false
+    }
+
+    /// Checks text outside of chat messages (e.g. resub messages) against the
+    /// blocked terms, there is nothing to delete or time out for these.
+    pub fn allow_text(&self, text: &str) -> bool {
+        !self.config.rules.iter().any(|rule| match &rule.filter {
+            Filter::BlockedTerms(terms) => contains_term(text, terms),
+            _ => false,
+        })
+    }
+
+    /// Blanks the viewer text of cheers, resub messages, redemptions and the
+    /// message a chat message replies to when `allow_text` refuses it. The
+    /// event itself still goes through, a blocked message shouldn't cost the
+    /// viewer their alert or hide a reply that is fine on its own.
+    pub fn scrub(&self, event: &mut Event) {
+        let (kind, text, emotes) = match event {
+            Event::ChannelChatMessageV1(Payload {
+                message: Message::Notification(p),
+                ..
+            }) => match &mut p.reply {
+                Some(reply) => ("reply", &mut reply.parent_message_body, None),
+                None => return,
+            },
+            Event::ChannelCheerV1(Payload {
+                message: Message::Notification(p),
+                ..
+            }) => ("cheer", &mut p.message, None),
+            Event::ChannelSubscriptionMessageV1(Payload {
+                message: Message::Notification(p),
+                ..
+            }) => ("resub", &mut p.message.text, Some(&mut p.message.emotes)),
+            Event::ChannelPointsCustomRewardRedemptionAddV1(Payload {
+                message: Message::Notification(p),
+                ..
+            }) => ("redemption", &mut p.user_input, None),
+            _ => return,
+        };
+        if !self.allow_text(text) {
+            info!("moderation: removed blocked text from {}", kind);
+            text.clear();
+            if let Some(emotes) = emotes {
+                emotes.clear();
+            }
+        }
+    }
+
+    async fn record(&self, user: &UserId) -> Vec<Instant> {
+        let window = self
+            .config
+            .rules
+            .iter()
+            .filter_map(|rule| match rule.filter {
+                Filter::Rate { seconds, .. } => Some(Duration::from_secs(seconds)),
+                _ => None,
+            })
+            .max();
+        let Some(window) = window else {
+            return Vec::new();
+        };
+        let mut recent = self.recent.lock().await;
+        recent.retain(|_, times| times.back().is_some_and(|t| t.elapsed() < window));
+        let times = recent.entry(user.clone()).or_default();
+        times.push_back(Instant::now());
+        while times.front().is_some_and(|t| t.elapsed() >= window) {
+            times.pop_front();
+        }
+        times.iter().copied().collect()
+    }
+}
+
+fn contains_term(text: &str, terms: &[String]) -> bool {
+    let text = text.to_lowercase();
+    terms.iter().any(|term| text.contains(&term.to_lowercase()))
+}
+
+fn contains_link(text: &str) -> bool {
+    text.split_whitespace().any(|word| {
+        let word = word.trim_matches(|c: char| !c.is_alphanumeric());
+        if word.contains("://") || word.starts_with("www.") {
+            return true;
+        }
+        // bare domains like example.com/path
+        let host = word.split('/').next().unwrap_or_default();
+        match host.rsplit_once('.') {
+            Some((name, tld)) => {
+                !name.is_empty()
+                    && !name.ends_with('.')
+                    && LINK_TLDS.contains(&tld.to_lowercase().as_str())
+            }
+            None => false,
+        }
+    })
+}
+
+fn caps_ratio(fragments: &[Fragment], min_length: usize) -> f32 {
+    let letters: Vec<char> = fragments
+        .iter()
+        .filter_map(|f| match f {
+            Fragment::Text { text } => Some(text),
+            _ => None,
+        })
+        .flat_map(|text| text.chars())
+        .filter(|c| c.is_alphabetic())
+        .collect();
+    if letters.is_empty() || letters.len() < min_length {
+        return 0.0;
+    }
+    let upper = letters.iter().filter(|c| c.is_uppercase()).count();
+    upper as f32 / letters.len() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use twitch_api::twitch_oauth2::UserToken;
+
+    fn moderation(rules: Vec<Rule>) -> Moderation {
+        let client = HelixClient::with_client(MeteredClient(reqwest::Client::new()));
+        let token = UserToken::from_existing_unchecked(
+            "token",
+            None,
+            "client",
+            None,
+            "streamer".into(),
+            "1".into(),
+            None,
+            None,
+        );
+        let config = ModerationConfig {
+            rules,
+            ..ModerationConfig::default()
+        };
+        Moderation::new(config, client, Arc::new(Mutex::new(token)))
+    }
+
+    fn reply_to(parent: &str) -> Event {
+        let payload = serde_json::json!({
+            "subscription": {
+                "id": "0b7f3361-672b-4d39-b307-dd5b576c9b27",
+                "status": "enabled",
+                "type": "channel.chat.message",
+                "version": "1",
+                "condition": { "broadcaster_user_id": "1", "user_id": "1" },
+                "transport": { "method": "websocket", "session_id": "session" },
+                "created_at": "2023-11-06T18:11:47.492253549Z",
+                "cost": 0
+            },
+            "event": {
+                "broadcaster_user_id": "1",
+                "broadcaster_user_login": "streamer",
+                "broadcaster_user_name": "streamer",
+                "chatter_user_id": "2",
+                "chatter_user_login": "viewer",
+                "chatter_user_name": "viewer",
+                "message_id": "reply",
+                "message": {
+                    "text": "@troll no",
+                    "fragments": [{ "type": "text", "text": "@troll no" }]
+                },
+                "color": "",
+                "badges": [],
+                "message_type": "text",
+                "cheer": null,
+                "reply": {
+                    "parent_message_id": "parent",
+                    "parent_message_body": parent,
+                    "parent_user_id": "3",
+                    "parent_user_name": "troll",
+                    "parent_user_login": "troll",
+                    "thread_message_id": "parent",
+                    "thread_user_id": "3",
+                    "thread_user_name": "troll",
+                    "thread_user_login": "troll"
+                },
+                "channel_points_custom_reward_id": null,
+                "source_broadcaster_user_id": null,
+                "source_broadcaster_user_login": null,
+                "source_broadcaster_user_name": null,
+                "source_message_id": null,
+                "source_badges": null
+            }
+        });
+        Event::parse(&payload.to_string()).unwrap()
+    }
+
+    fn parent_body(event: &Event) -> &str {
+        match event {
+            Event::ChannelChatMessageV1(Payload {
+                message: Message::Notification(p),
+                ..
+            }) => &p.reply.as_ref().unwrap().parent_message_body,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn scrubs_blocked_reply_parents() {
+        let moderation = moderation(vec![Rule {
+            filter: Filter::BlockedTerms(vec!["badword".to_string()]),
+            action: Action::Drop,
+        }]);
+
+        let mut blocked = reply_to("a BADWORD here");
+        moderation.scrub(&mut blocked);
+        assert_eq!(parent_body(&blocked), "");
+
+        let mut allowed = reply_to("hello");
+        moderation.scrub(&mut allowed);
+        assert_eq!(parent_body(&allowed), "hello");
+    }
+
+    fn text(text: &str) -> Fragment {
+        Fragment::Text {
+            text: text.to_string(),
+        }
+    }
+
+    fn emote(text: &str) -> Fragment {
+        serde_json::from_value(serde_json::json!({
+            "type": "emote",
+            "text": text,
+            "emote": { "id": "1", "emote_set_id": "0", "owner_id": "0", "format": ["static"] },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn links_with_scheme_or_www() {
+        assert!(contains_link("see https://example.invalid/page"));
+        assert!(contains_link("ftp://files"));
+        assert!(contains_link("go to www.example.xyz now"));
+    }
+
+    #[test]
+    fn bare_domains_with_known_tlds() {
+        assert!(contains_link("example.com"));
+        assert!(contains_link("follow me at twitch.tv/someone"));
+        assert!(contains_link("(example.GG)"));
+    }
+
+    #[test]
+    fn words_joined_by_dots_are_not_links() {
+        assert!(!contains_link("hi.there"));
+        assert!(!contains_link("end of a sentence.Next one"));
+        assert!(!contains_link("version 1.2.3"));
+        assert!(!contains_link("wait... what"));
+        assert!(!contains_link(".com"));
+        assert!(!contains_link("no links here"));
+    }
+
+    #[test]
+    fn caps_ratio_counts_letters_only() {
+        let fragments = [text("HELLO world 123!!")];
+        assert_eq!(caps_ratio(&fragments, 0), 0.5);
+        assert_eq!(caps_ratio(&[text("ABC")], 0), 1.0);
+    }
+
+    #[test]
+    fn caps_ratio_ignores_emotes() {
+        let fragments = [text("hi "), emote("KEKW"), text(" there")];
+        assert_eq!(caps_ratio(&fragments, 0), 0.0);
+    }
+
+    #[test]
+    fn caps_ratio_below_min_length_is_zero() {
+        assert_eq!(caps_ratio(&[text("LOL")], 5), 0.0);
+        assert_eq!(caps_ratio(&[text("LOUD")], 4), 1.0);
+        assert_eq!(caps_ratio(&[text("1234 !!")], 0), 0.0);
+    }
+}
@@ -0,0 +1,280 @@
+use serde::Deserialize;
+use std::{
+    collections::{HashSet, VecDeque},
+    future::Future,
+    time::Duration,
+};
+use tokio::{select, sync::mpsc::Receiver, time::Instant};
+use tracing::debug;
+use twitch_api::{eventsub::Event, types::Timestamp};
+
+/// How many recent message ids are remembered to drop redelivered notifications.
+const DEDUPE_WINDOW: usize = 1000;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct OrderingConfig {
+    /// Milliseconds notifications are held so ones that arrive late can be
+    /// put back in order, 0 passes them on right away.
+    pub window: u64,
+    /// Subscription types passed on without waiting, at the cost of ordering
+    /// them against the rest.
+    pub immediate: Vec<String>,
+}
+
+impl Default for OrderingConfig {
+    fn default() -> Self {
+        OrderingConfig {
+            window: 500,
+            // chat arrives in order and shouldn't lag behind the stream
+            immediate: vec!["channel.chat.message".to_string()],
+        }
+    }
+}
+
+pub struct Notification {
+    pub message_id: String,
+    pub subscription_type: String,
+    pub event: Event,
+    pub timestamp: Timestamp,
+}
+
+struct Held {
+    notification: Notification,
+    key: String,
+    release_at: Instant,
+}
+
+/// Drops notifications EventSub delivered more than once and hands the rest
+/// to `event_fn` one at a time, ordered by `message_timestamp` except for the
+/// `immediate` types.
+pub async fn run_ordered<Fut>(
+    config: OrderingConfig,
+    mut rx: Receiver<Notification>,
+    mut event_fn: impl FnMut(Event, Timestamp) -> Fut,
+) where
+    Fut: Future<Output = ()>,
+{
+    let mut seen = HashSet::<String>::new();
+    let mut seen_order = VecDeque::<String>::new();
+    let mut held = Vec::<Held>::new();
+    let window = Duration::from_millis(config.window);
+    loop {
+        let next_release = held.iter().map(|h| h.release_at).min();
+        select! {
+            notification = rx.recv() => {
+                let Some(notification) = notification else {
+                    break;
+                };
+                if !seen.insert(notification.message_id.clone()) {
+                    debug!("dropping duplicate notification {}", notification.message_id);
+                    continue;
+                }
+                seen_order.push_back(notification.message_id.clone());
+                if seen_order.len() > DEDUPE_WINDOW {
+                    seen.remove(&seen_order.pop_front().unwrap());
+                }
+                if window.is_zero() || config.immediate.contains(&notification.subscription_type) {
+                    event_fn(notification.event, notification.timestamp).await;
+                    continue;
+                }
+                held.push(Held {
+                    key: sort_key(notification.timestamp.as_str()),
+                    notification,
+                    release_at: Instant::now() + window,
+                });
+            }
+            _ = tokio::time::sleep_until(next_release.unwrap_or_else(Instant::now)), if next_release.is_some() => {
+                // release everything up to the newest timestamp that has waited long enough
+                let now = Instant::now();
+                let Some(until) = held
+                    .iter()
+                    .filter(|h| h.release_at <= now)
+                    .map(|h| h.key.clone())
+                    .max()
+                else {
+                    continue;
+                };
+                let (mut ready, rest): (Vec<_>, Vec<_>) =
+                    held.into_iter().partition(|h| h.key <= until);
+                held = rest;
+                ready.sort_by(|a, b| a.key.cmp(&b.key));
+                for h in ready {
+                    event_fn(h.notification.event, h.notification.timestamp).await;
+                }
+            }
+        }
+    }
+}
+
+/// RFC3339 timestamps with the fraction padded to nanoseconds, so they sort as strings.
+fn sort_key(timestamp: &str) -> String {
+    let timestamp = timestamp.trim_end_matches('Z');
+    match timestamp.split_once('.') {
+        Some((seconds, fraction)) => format!("{}.{:0<9}", seconds, fraction),
+        None => format!("{}.000000000", timestamp),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+    use tokio::{spawn, sync::mpsc, time::sleep};
+    use twitch_api::eventsub::EventsubWebsocketData;
+
+    fn notification(id: &str, timestamp: &str, subscription_type: &str) -> Notification {
+        let message = json!({
+            "metadata": {
+                "message_id": id,
+                "message_type": "notification",
+                "message_timestamp": timestamp,
+                "subscription_type": "stream.online",
+                "subscription_version": "1",
+            },
+            "payload": {
+                "subscription": {
+                    "id": "subscription",
+                    "status": "enabled",
+                    "type": "stream.online",
+                    "version": "1",
+                    "cost": 0,
+                    "condition": { "broadcaster_user_id": "1" },
+                    "transport": { "method": "websocket", "session_id": "session" },
+                    "created_at": timestamp,
+                },
+                "event": {
+                    "id": "stream",
+                    "broadcaster_user_id": "1",
+                    "broadcaster_user_login": "streamer",
+                    "broadcaster_user_name": "Streamer",
+                    "type": "live",
+                    "started_at": timestamp,
+                },
+            },
+        });
+        let EventsubWebsocketData::Notification { payload, .. } =
+            Event::parse_websocket(&message.to_string()).unwrap()
+        else {
+            panic!("not a notification");
+        };
+        Notification {
+            message_id: id.to_string(),
+            subscription_type: subscription_type.to_string(),
+            event: payload,
+            timestamp: Timestamp::try_from(timestamp).unwrap(),
+        }
+    }
+
+    /// Runs `run_ordered` on `notifications` and returns the timestamps in
+    /// the order they were handed on, as of `after`.
+    async fn order(
+        config: OrderingConfig,
+        notifications: Vec<Notification>,
+        after: Duration,
+    ) -> Vec<String> {
+        let (tx, rx) = mpsc::channel(100);
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let recorded = handled.clone();
+        spawn(run_ordered(config, rx, move |_, timestamp: Timestamp| {
+            recorded
+                .lock()
+                .unwrap()
+                .push(timestamp.as_str().to_string());
+            async {}
+        }));
+        for notification in notifications {
+            tx.send(notification).await.unwrap();
+        }
+        sleep(after).await;
+        handled.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn sort_key_pads_fractions() {
+        assert_eq!(
+            sort_key("2024-01-01T00:00:00.5Z"),
+            "2024-01-01T00:00:00.500000000"
+        );
+        assert_eq!(
+            sort_key("2024-01-01T00:00:00Z"),
+            "2024-01-01T00:00:00.000000000"
+        );
+        assert_eq!(
+            sort_key("2024-01-01T00:00:00.123456789Z"),
+            "2024-01-01T00:00:00.123456789"
+        );
+        // shorter fractions used to sort after longer ones
+        assert!(sort_key("2024-01-01T00:00:00.5Z") > sort_key("2024-01-01T00:00:00.45Z"));
+        assert!(sort_key("2024-01-01T00:00:00Z") < sort_key("2024-01-01T00:00:00.1Z"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reorders_late_notifications() {
+        let handled = order(
+            OrderingConfig::default(),
+            vec![
+                notification("b", "2024-01-01T00:00:01.5Z", "stream.online"),
+                notification("a", "2024-01-01T00:00:01.25Z", "stream.online"),
+            ],
+            Duration::from_millis(100),
+        )
+        .await;
+        assert!(handled.is_empty());
+
+        let handled = order(
+            OrderingConfig::default(),
+            vec![
+                notification("b", "2024-01-01T00:00:01.5Z", "stream.online"),
+                notification("a", "2024-01-01T00:00:01.25Z", "stream.online"),
+            ],
+            Duration::from_millis(600),
+        )
+        .await;
+        assert_eq!(
+            handled,
+            ["2024-01-01T00:00:01.25Z", "2024-01-01T00:00:01.5Z"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_duplicates() {
+        let handled = order(
+            OrderingConfig::default(),
+            vec![
+                notification("a", "2024-01-01T00:00:01Z", "stream.online"),
+                notification("a", "2024-01-01T00:00:01Z", "stream.online"),
+            ],
+            Duration::from_millis(600),
+        )
+        .await;
+        assert_eq!(handled, ["2024-01-01T00:00:01Z"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn passes_immediate_types_on() {
+        let handled = order(
+            OrderingConfig::default(),
+            vec![
+                notification("a", "2024-01-01T00:00:01Z", "stream.online"),
+                notification("b", "2024-01-01T00:00:02Z", "channel.chat.message"),
+            ],
+            Duration::from_millis(100),
+        )
+        .await;
+        assert_eq!(handled, ["2024-01-01T00:00:02Z"]);
+
+        let config = OrderingConfig {
+            window: 0,
+            ..OrderingConfig::default()
+        };
+        let handled = order(
+            config,
+            vec![notification("a", "2024-01-01T00:00:01Z", "stream.online")],
+            Duration::from_millis(1),
+        )
+        .await;
+        assert_eq!(handled, ["2024-01-01T00:00:01Z"]);
+    }
+}
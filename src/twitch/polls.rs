@@ -0,0 +1,452 @@
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{
+    select, spawn,
+    sync::{Mutex, mpsc},
+    task::JoinHandle,
+    time::{Instant, interval, sleep_until},
+};
+use tracing::{info, warn};
+use twitch_api::{
+    HelixClient,
+    eventsub::channel::chat::{Fragment, message::ChannelChatMessageV1Payload},
+    helix::polls::{
+        CreatePollBody, CreatePollRequest, EndPollBody, EndPollRequest, NewPollChoice,
+        end_poll::EndPoll,
+    },
+    twitch_oauth2::UserToken,
+    types::{PollChoice, PollId, PollStatus, UserId},
+};
+
+use super::chat::Chatter;
+use crate::{metrics::MeteredClient, page::PageSender};
+
+/// Most choices a native Twitch poll can have.
+const TWITCH_MAX_OPTIONS: usize = 5;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PollsConfig {
+    /// Milliseconds between `poll-tally` messages while votes come in.
+    pub snapshot_interval: u64,
+    /// Allow polls to be mirrored into a native Twitch poll.
+    pub twitch: bool,
+}
+
+impl Default for PollsConfig {
+    fn default() -> Self {
+        PollsConfig {
+            snapshot_interval: 1000,
+            twitch: false,
+        }
+    }
+}
+
+/// A `poll-start` message from the page.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PollRequest {
+    pub title: String,
+    pub options: Vec<String>,
+    /// Seconds, 15 to 1800 when mirrored to Twitch.
+    pub duration: u64,
+    #[serde(default)]
+    pub changeable: bool,
+    #[serde(default)]
+    pub weight: Weight,
+    /// Also run the poll as a native Twitch poll, which allows at most 5
+    /// options. Votes cast on Twitch are added to the chat votes.
+    #[serde(default)]
+    pub twitch: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Weight {
+    /// Every vote counts once.
+    #[default]
+    None,
+    /// Subscribers count once plus their tier.
+    Tier,
+    /// Votes count once plus the bits cheered with them.
+    Bits,
+}
+
+enum Input {
+    Start(PollRequest),
+    End,
+    Vote {
+        user: UserId,
+        text: String,
+        weight: Weights,
+    },
+    /// Vote totals of a native poll, from `channel.poll.progress` and
+    /// `channel.poll.end`.
+    TwitchVotes {
+        id: PollId,
+        choices: Vec<PollChoice>,
+    },
+}
+
+struct Weights {
+    tier: u8,
+    bits: i64,
+}
+
+struct ActivePoll {
+    id: u64,
+    request: PollRequest,
+    ends_at: Instant,
+    votes: HashMap<UserId, (usize, i64)>,
+    twitch_poll: Option<TwitchPoll>,
+    changed: bool,
+}
+
+struct TwitchPoll {
+    id: PollId,
+    /// Choice ids in the order of the poll's options.
+    choices: Vec<String>,
+    /// Votes cast on Twitch per option.
+    votes: Vec<i64>,
+}
+
+impl TwitchPoll {
+    fn update(&mut self, choices: &[PollChoice]) {
+        for choice in choices {
+            if let Some(i) = self.choices.iter().position(|id| *id == choice.id) {
+                self.votes[i] = choice.votes.unwrap_or(0);
+            }
+        }
+    }
+}
+
+/// Tallies chat votes for one poll at a time, see [`PollRequest`].
+#[derive(Clone)]
+pub struct PollEngine {
+    tx: mpsc::Sender<Input>,
+}
+
+impl PollEngine {
+    pub fn spawn(
+        config: PollsConfig,
+        page: PageSender,
+        client: HelixClient<'static, MeteredClient>,
+        token: Arc<Mutex<UserToken>>,
+    ) -> (Self, JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(100);
+        let handle = spawn(run(config, page, client, token, rx));
+        (PollEngine { tx }, handle)
+    }
+
+    pub async fn start(&self, request: PollRequest) {
+        self.tx.send(Input::Start(request)).await.unwrap();
+    }
+
+    pub async fn end(&self) {
+        self.tx.send(Input::End).await.unwrap();
+    }
+
+    pub async fn vote(&self, payload: &ChannelChatMessageV1Payload) {
+        let chatter = Chatter::from_badges(&payload.badges);
+        let weight = Weights {
+            tier: chatter.subscriber_tier.unwrap_or(0),
+            bits: payload.cheer.as_ref().map(|c| c.bits as i64).unwrap_or(0),
+        };
+        let vote = Input::Vote {
+            user: payload.chatter_user_id.clone(),
+            text: vote_text(&payload.message.fragments),
+            weight,
+        };
+        self.tx.send(vote).await.unwrap();
+    }
+
+    pub async fn twitch_votes(&self, id: PollId, choices: Vec<PollChoice>) {
+        self.tx
+            .send(Input::TwitchVotes { id, choices })
+            .await
+            .unwrap();
+    }
+}
+
+async fn run(
+    config: PollsConfig,
+    page: PageSender,
+    client: HelixClient<'static, MeteredClient>,
+    token: Arc<Mutex<UserToken>>,
+    mut rx: mpsc::Receiver<Input>,
+) {
+    let mut next_id = 0;
+    let mut poll: Option<ActivePoll> = None;
+    let mut snapshots = interval(Duration::from_millis(config.snapshot_interval.max(100)));
+
+    loop {
+        let ends_at = poll.as_ref().map(|p| p.ends_at);
+        select! {
+            input = rx.recv() => match input {
+                None => break,
+                Some(Input::Start(mut request)) => {
+                    if let Some(previous) = poll.take() {
+                        finish(&page, &client, &token, previous).await;
+                    }
+                    if request.options.len() < 2 {
+                        warn!("poll needs at least 2 options: {:?}", request);
+                        continue;
+                    }
+                    let mirrored = request.twitch && config.twitch;
+                    if mirrored {
+                        if request.options.len() > TWITCH_MAX_OPTIONS {
+                            warn!("twitch polls allow at most 5 options: {:?}", request);
+                            continue;
+                        }
+                        // both polls have to end together
+                        request.duration = request.duration.clamp(15, 1800);
+                    }
+                    next_id += 1;
+                    info!("starting poll {}: {}", next_id, request.title);
+                    let twitch_poll = if mirrored {
+                        create_twitch_poll(&client, &token, &request).await
+                    } else {
+                        None
+                    };
+                    let started = ActivePoll {
+                        id: next_id,
+                        ends_at: Instant::now() + Duration::from_secs(request.duration),
+                        request,
+                        votes: HashMap::new(),
+                        twitch_poll,
+                        changed: false,
+                    };
+                    page.send(tally("poll-start", &started)).await;
+                    poll = Some(started);
+                }
+                Some(Input::End) => {
+                    if let Some(previous) = poll.take() {
+                        finish(&page, &client, &token, previous).await;
+                    }
+                }
+                Some(Input::Vote { user, text, weight }) => {
+                    let Some(active) = poll.as_mut() else {
+                        continue;
+                    };
+                    let Some(option) = parse_vote(&text, &active.request.options) else {
+                        continue;
+                    };
+                    if !active.request.changeable && active.votes.contains_key(&user) {
+                        continue;
+                    }
+                    let weight = match active.request.weight {
+                        Weight::None => 1,
+                        Weight::Tier => 1 + weight.tier as i64,
+                        Weight::Bits => 1 + weight.bits,
+                    };
+                    active.votes.insert(user, (option, weight));
+                    active.changed = true;
+                }
+                Some(Input::TwitchVotes { id, choices }) => {
+                    if let Some(active) = poll.as_mut()
+                        && let Some(twitch_poll) = active.twitch_poll.as_mut().filter(|p| p.id == id)
+                    {
+                        twitch_poll.update(&choices);
+                        active.changed = true;
+                    }
+                }
+            },
+            _ = snapshots.tick(), if poll.as_ref().is_some_and(|p| p.changed) => {
+                let active = poll.as_mut().unwrap();
+                active.changed = false;
+                page.send(tally("poll-tally", active)).await;
+            }
+            _ = sleep_until(ends_at.unwrap_or_else(Instant::now)), if ends_at.is_some() => {
+                finish(&page, &client, &token, poll.take().unwrap()).await;
+            }
+        }
+    }
+}
+
+/// The message without its cheermotes, a cheer like `Cheer100 2` votes `2`.
+fn vote_text(fragments: &[Fragment]) -> String {
+    fragments
+        .iter()
+        .filter(|f| !matches!(f, Fragment::Cheermote { .. }))
+        .map(Fragment::text)
+        .collect()
+}
+
+/// A vote is an option's number (`2`, `#2`) or its text, ignoring case.
+fn parse_vote(text: &str, options: &[String]) -> Option<usize> {
+    let text = text.trim();
+    if let Ok(n) = text.trim_start_matches('#').parse::<usize>() {
+        return (1..=options.len()).contains(&n).then(|| n - 1);
+    }
+    options.iter().position(|o| o.eq_ignore_ascii_case(text))
+}
+
+async fn finish(
+    page: &PageSender,
+    client: &HelixClient<'static, MeteredClient>,
+    token: &Mutex<UserToken>,
+    mut poll: ActivePoll,
+) {
+    info!("ending poll {}", poll.id);
+    if let Some(twitch_poll) = poll.twitch_poll.as_mut() {
+        let token = token.lock().await;
+        let broadcaster_id = token.user_id.clone();
+        let body = EndPollBody::new(&broadcaster_id, &twitch_poll.id, PollStatus::Terminated);
+        match client.req_patch(EndPollRequest::new(), body, &*token).await {
+            // the final totals, `channel.poll.end` arrives after the tally
+            Ok(response) => {
+                if let EndPoll::Success(ended) = response.data {
+                    twitch_poll.update(&ended.choices);
+                }
+            }
+            Err(e) => warn!("failed to end twitch poll: {}", e),
+        }
+    }
+    let mut message = tally("poll-end", &poll);
+    let winners: Vec<Value> = {
+        let options = message["options"].as_array().unwrap();
+        let max = options.iter().filter_map(|o| o["score"].as_i64()).max();
+        options
+            .iter()
+            .filter(|o| max.is_some_and(|max| max > 0 && o["score"].as_i64() == Some(max)))
+            .map(|o| o["option"].clone())
+            .collect()
+    };
+    message["winners"] = json!(winners);
+    page.send(message).await;
+}
+
+async fn create_twitch_poll(
+    client: &HelixClient<'static, MeteredClient>,
+    token: &Mutex<UserToken>,
+    request: &PollRequest,
+) -> Option<TwitchPoll> {
+    let token = token.lock().await;
+    // twitch allows 60 characters per title and 25 per choice
+    let title: String = request.title.chars().take(60).collect();
+    let choices: Vec<NewPollChoice> = request
+        .options
+        .iter()
+        .map(|o| NewPollChoice::new(o.chars().take(25).collect::<String>()))
+        .collect();
+    let body = CreatePollBody::new(&token.user_id, title, request.duration as i64, choices);
+    match client
+        .req_post(CreatePollRequest::new(), body, &*token)
+        .await
+    {
+        Ok(response) => Some(TwitchPoll {
+            id: response.data.id,
+            votes: vec![0; response.data.choices.len()],
+            choices: response.data.choices.into_iter().map(|c| c.id).collect(),
+        }),
+        Err(e) => {
+            warn!("failed to create twitch poll: {}", e);
+            None
+        }
+    }
+}
+
+fn tally(kind: &str, poll: &ActivePoll) -> Value {
+    let options: Vec<Value> = poll
+        .request
+        .options
+        .iter()
+        .enumerate()
+        .map(|(i, option)| {
+            let votes = poll.votes.values().filter(|(o, _)| *o == i);
+            let twitch_votes = poll
+                .twitch_poll
+                .as_ref()
+                .and_then(|p| p.votes.get(i).copied())
+                .unwrap_or(0);
+            json!({
+                "option": option,
+                "votes": votes.clone().count() as i64 + twitch_votes,
+                "score": votes.map(|(_, w)| w).sum::<i64>() + twitch_votes,
+            })
+        })
+        .collect();
+    json!({
+        "type": kind,
+        "id": poll.id,
+        "title": poll.request.title,
+        "options": options,
+        "remaining": poll.ends_at.saturating_duration_since(Instant::now()).as_secs(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn options() -> Vec<String> {
+        ["Yes", "No", "Maybe"].map(String::from).to_vec()
+    }
+
+    #[test]
+    fn votes_by_number() {
+        assert_eq!(parse_vote("1", &options()), Some(0));
+        assert_eq!(parse_vote("#3", &options()), Some(2));
+        assert_eq!(parse_vote(" 2 ", &options()), Some(1));
+        assert_eq!(parse_vote("0", &options()), None);
+        assert_eq!(parse_vote("4", &options()), None);
+    }
+
+    #[test]
+    fn votes_by_text() {
+        assert_eq!(parse_vote("yes", &options()), Some(0));
+        assert_eq!(parse_vote("MAYBE", &options()), Some(2));
+        assert_eq!(parse_vote("yes please", &options()), None);
+        assert_eq!(parse_vote("", &options()), None);
+    }
+
+    #[test]
+    fn cheered_votes_skip_the_cheermote() {
+        let fragments: Vec<Fragment> = serde_json::from_value(json!([
+            {
+                "type": "cheermote",
+                "text": "Cheer100",
+                "cheermote": { "prefix": "cheer", "bits": 100, "tier": 100 },
+            },
+            { "type": "text", "text": " 2" },
+        ]))
+        .unwrap();
+        assert_eq!(vote_text(&fragments), " 2");
+        assert_eq!(parse_vote(&vote_text(&fragments), &options()), Some(1));
+    }
+
+    #[test]
+    fn tally_adds_twitch_votes() {
+        let request: PollRequest = serde_json::from_value(json!({
+            "title": "?",
+            "options": ["Yes", "No"],
+            "duration": 60,
+        }))
+        .unwrap();
+        let mut twitch_poll = TwitchPoll {
+            id: "poll".into(),
+            choices: vec!["a".to_string(), "b".to_string()],
+            votes: vec![0, 0],
+        };
+        let choices: Vec<PollChoice> = serde_json::from_value(json!([
+            { "id": "b", "title": "No", "votes": 4 },
+            { "id": "c", "title": "Other", "votes": 9 },
+        ]))
+        .unwrap();
+        twitch_poll.update(&choices);
+        let poll = ActivePoll {
+            id: 1,
+            request,
+            ends_at: Instant::now(),
+            votes: HashMap::from([("1".into(), (0, 2)), ("2".into(), (1, 1))]),
+            twitch_poll: Some(twitch_poll),
+            changed: false,
+        };
+        let message = tally("poll-tally", &poll);
+        assert_eq!(message["options"][0]["votes"], 1);
+        assert_eq!(message["options"][0]["score"], 2);
+        assert_eq!(message["options"][1]["votes"], 5);
+        assert_eq!(message["options"][1]["score"], 5);
+    }
+}
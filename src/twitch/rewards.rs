@@ -0,0 +1,331 @@
+use serde::Deserialize;
+use serde_json::json;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{
+    select, spawn,
+    sync::{Mutex, mpsc},
+    task::JoinHandle,
+    time::{Instant, sleep_until},
+};
+use tracing::{info, warn};
+use twitch_api::{
+    HelixClient,
+    eventsub::channel::ChannelPointsCustomRewardRedemptionAddV1Payload,
+    helix::points::{
+        CreateCustomRewardBody, CreateCustomRewardRequest, CustomReward,
+        CustomRewardRedemptionStatus, GetCustomRewardRequest, UpdateCustomRewardBody,
+        UpdateCustomRewardRequest, UpdateRedemptionStatusBody, UpdateRedemptionStatusRequest,
+    },
+    twitch_oauth2::UserToken,
+    types::{RedemptionId, RewardId},
+};
+
+use crate::{metrics::MeteredClient, page::PageSender};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RewardsConfig {
+    /// Rewards created or updated (matched by title) at startup.
+    pub list: Vec<RewardConfig>,
+    /// Seconds to wait for the page to fulfill or cancel a `page` redemption
+    /// before it is canceled.
+    pub timeout: u64,
+}
+
+impl Default for RewardsConfig {
+    fn default() -> Self {
+        RewardsConfig {
+            list: Vec::new(),
+            timeout: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RewardConfig {
+    pub title: String,
+    pub cost: usize,
+    #[serde(default)]
+    pub prompt: Option<String>,
+    /// Hex color like `#9147FF`.
+    #[serde(default)]
+    pub color: Option<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub user_input: bool,
+    /// Seconds.
+    #[serde(default)]
+    pub global_cooldown: Option<usize>,
+    #[serde(default)]
+    pub max_per_stream: Option<usize>,
+    #[serde(default)]
+    pub max_per_user: Option<usize>,
+    /// Only redeemable in these scenes, paused otherwise. Always redeemable if
+    /// left out.
+    #[serde(default)]
+    pub scenes: Option<Vec<String>>,
+    #[serde(default)]
+    pub fulfill: Fulfill,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Fulfill {
+    /// Left in the reward queue for the streamer.
+    #[default]
+    Manual,
+    /// Fulfilled as soon as it is redeemed.
+    Auto,
+    /// Fulfilled or canceled by the page, canceled if it doesn't answer in time.
+    Page,
+}
+
+enum Input {
+    Redeemed {
+        id: RedemptionId,
+        reward_id: RewardId,
+    },
+    Resolve {
+        id: RedemptionId,
+        fulfilled: bool,
+    },
+    Scene(String),
+}
+
+struct Managed {
+    id: RewardId,
+    config: RewardConfig,
+}
+
+/// Keeps the configured channel point rewards in sync with Twitch and
+/// resolves their redemptions, refunding the points of canceled ones.
+#[derive(Clone)]
+pub struct Rewards {
+    tx: mpsc::Sender<Input>,
+}
+
+impl Rewards {
+    pub fn spawn(
+        config: RewardsConfig,
+        page: PageSender,
+        client: HelixClient<'static, MeteredClient>,
+        token: Arc<Mutex<UserToken>>,
+    ) -> (Self, JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(100);
+        let handle = spawn(run(config, page, client, token, rx));
+        (Rewards { tx }, handle)
+    }
+
+    pub async fn redeemed(&self, payload: &ChannelPointsCustomRewardRedemptionAddV1Payload) {
+        let input = Input::Redeemed {
+            id: payload.id.clone(),
+            reward_id: payload.reward.id.clone(),
+        };
+        self.tx.send(input).await.unwrap();
+    }
+
+    /// Marks a redemption fulfilled, or canceled and refunded.
+    pub async fn resolve(&self, id: RedemptionId, fulfilled: bool) {
+        self.tx
+            .send(Input::Resolve { id, fulfilled })
+            .await
+            .unwrap();
+    }
+
+    /// Pauses and unpauses rewards that are limited to some scenes.
+    pub async fn scene(&self, scene: &str) {
+        self.tx.send(Input::Scene(scene.to_string())).await.unwrap();
+    }
+}
+
+async fn run(
+    config: RewardsConfig,
+    page: PageSender,
+    client: HelixClient<'static, MeteredClient>,
+    token: Arc<Mutex<UserToken>>,
+    mut rx: mpsc::Receiver<Input>,
+) {
+    let managed = sync(&config, &client, &token).await;
+    let timeout = Duration::from_secs(config.timeout);
+    // page redemptions waiting for an answer
+    let mut pending = HashMap::<RedemptionId, (RewardId, Instant)>::new();
+
+    loop {
+        let next_timeout = pending
+            .iter()
+            .min_by_key(|(_, (_, deadline))| *deadline)
+            .map(|(id, (_, deadline))| (id.clone(), *deadline));
+        let deadline = next_timeout.as_ref().map(|(_, deadline)| *deadline);
+        select! {
+            input = rx.recv() => match input {
+                None => break,
+                Some(Input::Redeemed { id, reward_id }) => {
+                    let Some(reward) = managed.iter().find(|m| m.id == reward_id) else {
+                        continue;
+                    };
+                    match reward.config.fulfill {
+                        Fulfill::Manual => {}
+                        Fulfill::Auto => {
+                            let status = CustomRewardRedemptionStatus::Fulfilled;
+                            update_status(&client, &token, &reward_id, &id, status).await;
+                        }
+                        Fulfill::Page => {
+                            pending.insert(id, (reward_id, Instant::now() + timeout));
+                        }
+                    }
+                }
+                Some(Input::Resolve { id, fulfilled }) => {
+                    let Some((reward_id, _)) = pending.remove(&id) else {
+                        warn!("no pending redemption {}", id);
+                        continue;
+                    };
+                    let status = if fulfilled {
+                        CustomRewardRedemptionStatus::Fulfilled
+                    } else {
+                        CustomRewardRedemptionStatus::Canceled
+                    };
+                    update_status(&client, &token, &reward_id, &id, status).await;
+                }
+                Some(Input::Scene(scene)) => {
+                    for reward in &managed {
+                        let Some(scenes) = &reward.config.scenes else {
+                            continue;
+                        };
+                        let mut body = UpdateCustomRewardBody::default();
+                        body.is_paused = Some(!scenes.contains(&scene));
+                        update_reward(&client, &token, &reward.id, body).await;
+                    }
+                }
+            },
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                let (id, _) = next_timeout.unwrap();
+                let (reward_id, _) = pending.remove(&id).unwrap();
+                warn!("redemption {} timed out, refunding", id);
+                let status = CustomRewardRedemptionStatus::Canceled;
+                update_status(&client, &token, &reward_id, &id, status).await;
+                let message = json!({ "type": "redemption-timeout", "id": id, "reward_id": reward_id });
+                page.send(message).await;
+            }
+        }
+    }
+}
+
+/// Creates the configured rewards that don't exist yet and updates the rest.
+/// Only rewards created by this client id can be managed.
+async fn sync(
+    config: &RewardsConfig,
+    client: &HelixClient<'static, MeteredClient>,
+    token: &Mutex<UserToken>,
+) -> Vec<Managed> {
+    if config.list.is_empty() {
+        return Vec::new();
+    }
+    let token = token.lock().await;
+    let request =
+        GetCustomRewardRequest::broadcaster_id(&token.user_id).only_manageable_rewards(true);
+    let existing: Vec<CustomReward> = match client.req_get(request, &*token).await {
+        Ok(response) => response.data,
+        Err(e) => {
+            warn!("failed to get channel point rewards: {}", e);
+            return Vec::new();
+        }
+    };
+    let mut managed = Vec::new();
+    for reward in &config.list {
+        let id = match existing.iter().find(|r| r.title == reward.title) {
+            Some(existing) => {
+                let mut body = UpdateCustomRewardBody::default();
+                body.cost = Some(reward.cost);
+                body.prompt = reward.prompt.clone().map(Into::into);
+                body.background_color = reward.color.clone().map(Into::into);
+                body.is_enabled = Some(reward.enabled);
+                body.is_user_input_required = Some(reward.user_input);
+                body.is_global_cooldown_enabled = Some(reward.global_cooldown.is_some());
+                body.global_cooldown_seconds = reward.global_cooldown;
+                body.is_max_per_stream_enabled = Some(reward.max_per_stream.is_some());
+                body.max_per_stream = reward.max_per_stream;
+                body.is_max_per_user_per_stream_enabled = Some(reward.max_per_user.is_some());
+                body.max_per_user_per_stream = reward.max_per_user;
+                // scene limited rewards stay paused until one of their scenes is shown
+                body.is_paused = Some(reward.scenes.is_some());
+                let request = UpdateCustomRewardRequest::new(&token.user_id, &existing.id);
+                if let Err(e) = client.req_patch(request, body, &*token).await {
+                    warn!("failed to update reward {}: {}", reward.title, e);
+                }
+                existing.id.clone()
+            }
+            None => {
+                let mut body = CreateCustomRewardBody::new(reward.title.as_str(), reward.cost);
+                body.prompt = reward.prompt.clone().map(Into::into);
+                body.background_color = reward.color.clone().map(Into::into);
+                body.is_enabled = Some(reward.enabled);
+                body.is_user_input_required = Some(reward.user_input);
+                body.is_global_cooldown_enabled = Some(reward.global_cooldown.is_some());
+                body.global_cooldown_seconds = reward.global_cooldown;
+                body.is_max_per_stream_enabled = Some(reward.max_per_stream.is_some());
+                body.max_per_stream = reward.max_per_stream;
+                body.is_max_per_user_per_stream_enabled = Some(reward.max_per_user.is_some());
+                body.max_per_user_per_stream = reward.max_per_user;
+                let request = CreateCustomRewardRequest::broadcaster_id(&token.user_id);
+                match client.req_post(request, body, &*token).await {
+                    Ok(response) if reward.scenes.is_some() => {
+                        // can't be created paused
+                        let mut body = UpdateCustomRewardBody::default();
+                        body.is_paused = Some(true);
+                        let request =
+                            UpdateCustomRewardRequest::new(&token.user_id, &response.data.id);
+                        if let Err(e) = client.req_patch(request, body, &*token).await {
+                            warn!("failed to pause reward {}: {}", reward.title, e);
+                        }
+                        response.data.id
+                    }
+                    Ok(response) => response.data.id,
+                    Err(e) => {
+                        warn!("failed to create reward {}: {}", reward.title, e);
+                        continue;
+                    }
+                }
+            }
+        };
+        info!("managing reward {} ({})", reward.title, id);
+        managed.push(Managed {
+            id,
+            config: reward.clone(),
+        });
+    }
+    managed
+}
+
+async fn update_reward(
+    client: &HelixClient<'static, MeteredClient>,
+    token: &Mutex<UserToken>,
+    id: &RewardId,
+    body: UpdateCustomRewardBody<'_>,
+) {
+    let token = token.lock().await;
+    let request = UpdateCustomRewardRequest::new(&token.user_id, id);
+    if let Err(e) = client.req_patch(request, body, &*token).await {
+        warn!("failed to update reward {}: {}", id, e);
+    }
+}
+
+async fn update_status(
+    client: &HelixClient<'static, MeteredClient>,
+    token: &Mutex<UserToken>,
+    reward_id: &RewardId,
+    id: &RedemptionId,
+    status: CustomRewardRedemptionStatus,
+) {
+    info!("redemption {}: {:?}", id, status);
+    let token = token.lock().await;
+    let request = UpdateRedemptionStatusRequest::new(&token.user_id, reward_id, id);
+    let body = UpdateRedemptionStatusBody::status(status);
+    if let Err(e) = client.req_patch(request, body, &*token).await {
+        warn!("failed to update redemption {}: {}", id, e);
+    }
+}
@@ -0,0 +1,149 @@
+use serde::Deserialize;
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    spawn,
+    sync::{
+        Mutex,
+        mpsc::{self, error::TrySendError},
+    },
+    task::JoinHandle,
+    time::sleep_until,
+};
+use tracing::{debug, info, warn};
+use twitch_api::{
+    HelixClient,
+    twitch_oauth2::{TwitchToken, UserToken},
+    types::{MsgId, UserId},
+};
+
+use crate::metrics::{self, MeteredClient};
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ChatConfig {
+    /// Messages per 30 seconds. Twitch allows 20 for regular users and 100
+    /// when the sender is a moderator or the broadcaster.
+    pub rate_limit: usize,
+    /// Authenticate a second account to send messages as.
+    pub bot: bool,
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        ChatConfig {
+            rate_limit: 20,
+            bot: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct OutgoingMessage {
+    broadcaster_id: UserId,
+    text: String,
+    reply_to: Option<MsgId>,
+}
+
+/// Queues chat messages and sends them through Helix without exceeding the
+/// chat rate limit. Queuing never waits, messages that don't fit in the queue
+/// are dropped so a backed up rate limit can't stall the callers.
+#[derive(Clone)]
+pub struct ChatSender {
+    tx: mpsc::Sender<OutgoingMessage>,
+}
+
+impl ChatSender {
+    pub fn spawn(
+        client: HelixClient<'static, MeteredClient>,
+        token: Arc<Mutex<UserToken>>,
+        rate_limit: usize,
+    ) -> (Self, JoinHandle<()>) {
+        let (tx, mut rx) = mpsc::channel::<OutgoingMessage>(100);
+        let handle = spawn(async move {
+            let mut sent = VecDeque::<Instant>::new();
+            while let Some(message) = rx.recv().await {
+                while sent
+                    .front()
+                    .is_some_and(|t| t.elapsed() >= RATE_LIMIT_WINDOW)
+                {
+                    sent.pop_front();
+                }
+                if sent.len() >= rate_limit.max(1) {
+                    let oldest = sent.pop_front().unwrap();
+                    debug!("chat rate limit reached, waiting");
+                    sleep_until((oldest + RATE_LIMIT_WINDOW).into()).await;
+                }
+                sent.push_back(Instant::now());
+
+                let token = token.lock().await;
+                let sender_id = token.user_id().unwrap().to_owned();
+                let result = match &message.reply_to {
+                    Some(parent) => {
+                        client
+                            .send_chat_message_reply(
+                                &message.broadcaster_id,
+                                &sender_id,
+                                parent,
+                                message.text.as_str(),
+                                &*token,
+                            )
+                            .await
+                    }
+                    None => {
+                        client
+                            .send_chat_message(
+                                &message.broadcaster_id,
+                                &sender_id,
+                                message.text.as_str(),
+                                &*token,
+                            )
+                            .await
+                    }
+                };
+                match result {
+                    Ok(response) if response.is_sent => {
+                        info!("sent chat message: {}", message.text)
+                    }
+                    Ok(response) => warn!(
+                        "chat message dropped: {:?}, {:?}",
+                        message.text, response.drop_reason
+                    ),
+                    Err(e) => warn!("failed to send chat message: {}", e),
+                }
+            }
+        });
+        (ChatSender { tx }, handle)
+    }
+
+    pub fn send(&self, broadcaster_id: &UserId, text: &str) {
+        self.enqueue(broadcaster_id, text, None);
+    }
+
+    pub fn reply(&self, broadcaster_id: &UserId, parent: &MsgId, text: &str) {
+        self.enqueue(broadcaster_id, text, Some(parent.clone()));
+    }
+
+    fn enqueue(&self, broadcaster_id: &UserId, text: &str, reply_to: Option<MsgId>) {
+        let message = OutgoingMessage {
+            broadcaster_id: broadcaster_id.clone(),
+            text: text.to_string(),
+            reply_to,
+        };
+        match self.tx.try_send(message) {
+            Ok(()) => {}
+            Err(TrySendError::Full(message)) => {
+                warn!("chat queue full, dropping message: {}", message.text);
+                metrics::inc("webstreamer_chat_dropped_messages_total", &[]);
+            }
+            Err(TrySendError::Closed(message)) => {
+                warn!("chat sender stopped, dropping message: {}", message.text);
+            }
+        }
+    }
+}
@@ -1,52 +1,120 @@
-use futures::stream::SplitSink;
+use futures::SinkExt;
 use futures_util::StreamExt;
-use std::net::SocketAddr;
-use tokio::{
-    net::{TcpListener, TcpStream},
-    spawn,
-    sync::mpsc,
-    task::JoinHandle,
-};
+use std::{net::SocketAddr, time::Duration};
+use tokio::{net::TcpListener, pin, select, spawn, sync::mpsc, task::JoinHandle, time::sleep};
 use tokio_tungstenite::{
-    WebSocketStream, accept_async,
+    accept_async,
     tungstenite::{Bytes, Message},
 };
-use tracing::info;
+use tracing::{info, warn};
+
+use crate::{
+    metrics,
+    page::{PageFilter, PageSender},
+    protocol::PageMessage,
+    status::{Status, unix_now},
+};
+
+/// How long a page has to subscribe or ask for history before it gets
+/// everything.
+const START_TIMEOUT: Duration = Duration::from_secs(1);
 
+/// Accepts the extension's connection, forwarding captured media to
+/// `stream_tx`, messages from the page to `page_tx` and messages from
+/// `page_sender` to the page. The extension reconnects whenever the page
+/// reloads, so connections are accepted one after another, each starting with
+/// the backlog once the page subscribed, asked for history or `START_TIMEOUT`
+/// passed.
 pub async fn run_ws_stream(
     port: u16,
     stream_tx: mpsc::Sender<Bytes>,
-) -> (
-    JoinHandle<()>,
-    SplitSink<WebSocketStream<TcpStream>, Message>,
-) {
+    page_tx: mpsc::Sender<PageMessage>,
+    page_sender: PageSender,
+    status: Status,
+) -> JoinHandle<()> {
     let addr = format!("0.0.0.0:{}", port).parse::<SocketAddr>().unwrap();
     let listener = TcpListener::bind(&addr).await.unwrap();
     info!("ws listening on: {}", addr);
-    let (stream, _) = listener.accept().await.unwrap();
-    let ws_stream = accept_async(stream).await.unwrap();
-    let (ws_sender, mut ws_receiver) = ws_stream.split();
-    info!("ws connection established");
-    let handle = spawn(async move {
-        while let Some(msg) = ws_receiver.next().await {
-            match msg {
-                Ok(Message::Binary(data)) => {
-                    stream_tx.send(data).await.unwrap();
-                }
-                Ok(Message::Text(text)) => {
-                    info!("ws received: {}", text);
-                }
-                Ok(Message::Close(_)) => {
-                    println!("ws connection closed");
-                    break;
-                }
+    spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let ws_stream = match accept_async(stream).await {
+                Ok(ws_stream) => ws_stream,
                 Err(e) => {
-                    println!("ws error: {}", e);
-                    break;
+                    warn!("ws handshake failed: {}", e);
+                    continue;
+                }
+            };
+            let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+            info!("ws connection established");
+            let mut page_rx = page_sender.connect().await;
+            let start = sleep(START_TIMEOUT);
+            pin!(start);
+            let mut waiting = true;
+            status.update(|s| {
+                s.extension.connected = true;
+                s.extension.connections += 1;
+            });
+            loop {
+                select! {
+                    msg = ws_receiver.next() => match msg {
+                        Some(Ok(Message::Binary(data))) => {
+                            status.update(|s| {
+                                s.extension.bytes += data.len() as u64;
+                                s.extension.last_data_at = Some(unix_now());
+                            });
+                            let bytes = data.len() as f64;
+                            metrics::add("webstreamer_extension_bytes_total", &[], bytes);
+                            metrics::inc("webstreamer_extension_frames_total", &[]);
+                            stream_tx.send(data).await.unwrap();
+                            let depth = stream_tx.max_capacity() - stream_tx.capacity();
+                            metrics::set("webstreamer_stream_queue_depth", &[], depth as f64);
+                        }
+                        Some(Ok(Message::Text(text))) => {
+                            match serde_json::from_str::<PageMessage>(&text) {
+                                Ok(PageMessage::History { since }) => {
+                                    page_sender.history(since).await;
+                                }
+                                Ok(PageMessage::Subscribe {
+                                    categories,
+                                    channels,
+                                    rewards,
+                                    since,
+                                }) => {
+                                    let filter = PageFilter {
+                                        categories,
+                                        channels,
+                                        rewards,
+                                    };
+                                    page_sender.subscribe(filter, since.unwrap_or(0)).await;
+                                }
+                                Ok(message) => page_tx.send(message).await.unwrap(),
+                                Err(e) => warn!("invalid page message {}: {}", text, e),
+                            }
+                        }
+                        Some(Ok(Message::Close(_))) | None => {
+                            info!("ws connection closed");
+                            break;
+                        }
+                        Some(Err(e)) => {
+                            warn!("ws error: {}", e);
+                            break;
+                        }
+                        _ => {}
+                    },
+                    _ = &mut start, if waiting => {
+                        waiting = false;
+                        page_sender.start().await;
+                    }
+                    Some(message) = page_rx.recv() => {
+                        if let Err(e) = ws_sender.send(Message::text(message)).await {
+                            warn!("ws send failed: {}", e);
+                            break;
+                        }
+                    }
                 }
-                _ => {}
             }
+            status.update(|s| s.extension.connected = false);
         }
-    });
-    (handle, ws_sender)
+    })
 }